@@ -0,0 +1,144 @@
+use crate::config::CONFIG;
+use crate::plugin::{log_message, LOG_WARN};
+use crate::server_info::AUTH_FAILURES;
+use crate::statsd;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use warp::{Filter, Rejection};
+
+/// What a set of credentials may do, every role includes the ones before it
+#[derive(Deserialize, Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Read-only access to endpoints that aren't public
+    Viewer,
+    /// Chat, kicks and bans
+    Moderator,
+    /// Every admin command
+    Admin,
+}
+
+/// Who made a request, the name ends up in logs and as the issuer of bans
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub name: String,
+    pub role: Role,
+    /// Requested a public endpoint without credentials, never gets private messages
+    pub anonymous: bool,
+}
+
+impl Identity {
+    fn anonymous() -> Identity {
+        Identity {
+            name: "anonymous".to_string(),
+            role: Role::Viewer,
+            anonymous: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+#[derive(Debug)]
+pub struct Forbidden;
+
+impl warp::reject::Reject for Forbidden {}
+
+/// Compares in constant time so tokens can't be guessed byte by byte
+fn secure_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Checks a bearer token or basic auth `Authorization` header against the config
+fn authenticate(authorization: &str) -> Option<Identity> {
+    let auth = &CONFIG.auth;
+
+    if let Some(bearer) = authorization.strip_prefix("Bearer ") {
+        return auth
+            .tokens
+            .iter()
+            .find(|token| secure_eq(&token.token, bearer.trim()))
+            .map(|token| Identity {
+                name: token.name.clone(),
+                role: token.role,
+                anonymous: false,
+            });
+    }
+
+    let basic = authorization.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(basic.trim()).ok()?).ok()?;
+    let mut parts = decoded.splitn(2, ':');
+    let (name, password) = (parts.next()?, parts.next()?);
+
+    auth.users
+        .iter()
+        .find(|user| user.name == name && secure_eq(&user.password, password))
+        .map(|user| Identity {
+            name: user.name.clone(),
+            role: user.role,
+            anonymous: false,
+        })
+}
+
+fn fail(reason: &str, endpoint: &str, remote: Option<SocketAddr>) {
+    AUTH_FAILURES.with_label_values(&[reason]).inc();
+    statsd::count("http.auth_failures", 1.0, &[("reason", reason)]);
+    log_message(
+        LOG_WARN,
+        format!(
+            "Authentication failed ({}) for {} from {}",
+            reason,
+            endpoint,
+            remote.map_or("unknown".to_string(), |remote| remote.to_string())
+        )
+        .as_str(),
+    );
+}
+
+/// Requires credentials with at least `role`, viewer endpoints listed in `auth.public` are open to everyone
+/// but still reject invalid credentials
+pub fn require(
+    role: Role,
+    endpoint: &'static str,
+) -> impl Filter<Extract = (Identity,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::addr::remote())
+        .and_then(
+            move |authorization: Option<String>, remote: Option<SocketAddr>| async move {
+                let public =
+                    role == Role::Viewer && CONFIG.auth.public.iter().any(|p| p == endpoint);
+
+                // Credentials are checked on public endpoints too, they unlock private messages
+                let identity = match authorization {
+                    None if public => return Ok(Identity::anonymous()),
+                    None => {
+                        // Not logged, browsers ask without credentials first
+                        AUTH_FAILURES.with_label_values(&["missing"]).inc();
+                        statsd::count("http.auth_failures", 1.0, &[("reason", "missing")]);
+                        return Err(warp::reject::custom(Unauthorized));
+                    }
+                    Some(authorization) => match authenticate(&authorization) {
+                        Some(identity) => identity,
+                        None => {
+                            fail("invalid", endpoint, remote);
+                            return Err(warp::reject::custom(Unauthorized));
+                        }
+                    },
+                };
+
+                if identity.role < role {
+                    fail("forbidden", endpoint, remote);
+                    return Err(warp::reject::custom(Forbidden));
+                }
+
+                Ok(identity)
+            },
+        )
+}
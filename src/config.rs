@@ -0,0 +1,714 @@
+use crate::auth::Role;
+use crate::plugin::{get_mod_dir, log_message, LOG_INFO, LOG_WARN};
+use crate::push::PushMode;
+use crate::server_info::OverflowPolicy;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use std::env;
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+lazy_static! {
+    pub static ref CONFIG: Config = Config::load();
+}
+
+const DEFAULT_LOGIN_MESSAGE: &str = "#ff0000This server runs #0000ffnwahttp#ff0000 and this is it's obnoxious login message for #00ff00you#ff0000!!\n";
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Config {
+    pub address: String,
+    pub port: u16,
+    pub www_dir: Option<String>,
+    pub ban_file: Option<String>,
+    pub tick_interval: u32,
+    pub low_frequency_divisor: u64,
+    /// Movement further than this between two ticks is a teleport and doesn't count as travelled
+    pub max_distance_per_tick: f64,
+    pub login_message: String,
+    pub endpoints: EndpointConfig,
+    pub auth: AuthConfig,
+    pub tls: TlsConfig,
+    pub metrics: MetricsConfig,
+    pub push: PushConfig,
+    pub statsd: StatsdConfig,
+    pub websocket: WebsocketConfig,
+    pub sse: SseConfig,
+    pub announcements: AnnouncementConfig,
+    pub chat: ChatConfig,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ChatConfig {
+    /// Amount of chat messages kept for `/api/chat`
+    pub history_size: usize,
+    /// Messages starting with one of these commands are private messages
+    pub private_commands: Vec<String>,
+    /// Regexes replaced by `[redacted]` in private messages
+    pub redact: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Label player metrics with a hash of the name instead of the name
+    pub hash_player_names: bool,
+    /// Prepended to names before hashing, so hashes can't be matched against a list of names
+    pub hash_salt: String,
+}
+
+/// Pushing metrics for setups where Prometheus can't scrape the server
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PushConfig {
+    pub enabled: bool,
+    pub mode: PushMode,
+    pub url: String,
+    pub job: String,
+    /// Defaults to `$HOSTNAME`
+    pub instance: String,
+    /// Seconds between pushes
+    pub interval: u64,
+    /// Retries of a failed push before waiting for the next one
+    pub max_retries: u32,
+}
+
+/// Mirrors the Prometheus metrics to a StatsD daemon over UDP
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct StatsdConfig {
+    pub enabled: bool,
+    pub address: String,
+    /// Prepended to every metric name
+    pub prefix: String,
+    /// Send labels as DogStatsD tags instead of appending them to the name
+    pub dogstatsd: bool,
+    /// `key:value` tags added to every metric, DogStatsD only
+    pub tags: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    /// PEM certificate chain
+    pub cert: String,
+    /// PEM private key
+    pub key: String,
+    /// Seconds between checks of the certificate and key for changes
+    pub reload_interval: u64,
+    /// Plain HTTP port that redirects to HTTPS
+    pub redirect_port: Option<u16>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Read-only endpoints that don't need credentials, named like in `[endpoints]`
+    pub public: Vec<String>,
+    pub tokens: Vec<TokenConfig>,
+    pub users: Vec<UserConfig>,
+}
+
+/// A static bearer token
+#[derive(Deserialize, Clone, Debug)]
+pub struct TokenConfig {
+    pub name: String,
+    pub token: String,
+    pub role: Role,
+}
+
+/// Credentials for HTTP basic auth
+#[derive(Deserialize, Clone, Debug)]
+pub struct UserConfig {
+    pub name: String,
+    pub password: String,
+    pub role: Role,
+}
+
+/// Endpoints that are public by default, chat includes private messages so it needs credentials
+const DEFAULT_PUBLIC: &[&str] = &[
+    "info",
+    "players",
+    "cells",
+    "websocket",
+    "sse",
+    "metrics",
+    "www",
+];
+
+/// Endpoints that only read and can be made public
+const READ_ENDPOINTS: &[&str] = &[
+    "info",
+    "players",
+    "cells",
+    "chat",
+    "websocket",
+    "sse",
+    "metrics",
+    "www",
+];
+
+/// In-game messages sent to everyone when a player improves
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct AnnouncementConfig {
+    pub level_up: bool,
+    pub skill_increase: bool,
+    pub attribute_increase: bool,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SseConfig {
+    /// Amount of lifecycle, delta and chat events kept for `Last-Event-ID` resumption
+    pub history_size: usize,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct WebsocketConfig {
+    /// Max frames queued per client before the overflow policy kicks in
+    pub queue_size: usize,
+    pub overflow: OverflowPolicy,
+    /// Seconds between pings
+    pub ping_interval: u64,
+    /// Seconds without any frame from the client before it's disconnected
+    pub idle_timeout: u64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct EndpointConfig {
+    pub info: bool,
+    pub players: bool,
+    pub cells: bool,
+    pub websocket: bool,
+    pub sse: bool,
+    pub chat: bool,
+    pub commands: bool,
+    pub bans: bool,
+    pub metrics: bool,
+    pub www: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: "::".to_string(),
+            port: 8787,
+            www_dir: None,
+            ban_file: None,
+            tick_interval: 50,
+            low_frequency_divisor: 20,
+            max_distance_per_tick: 500.0,
+            login_message: DEFAULT_LOGIN_MESSAGE.to_string(),
+            endpoints: EndpointConfig::default(),
+            auth: AuthConfig::default(),
+            tls: TlsConfig::default(),
+            metrics: MetricsConfig::default(),
+            push: PushConfig::default(),
+            statsd: StatsdConfig::default(),
+            websocket: WebsocketConfig::default(),
+            sse: SseConfig::default(),
+            announcements: AnnouncementConfig::default(),
+            chat: ChatConfig::default(),
+        }
+    }
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        PushConfig {
+            enabled: false,
+            mode: PushMode::Pushgateway,
+            url: String::new(),
+            job: "tes3mp".to_string(),
+            instance: String::new(),
+            interval: 15,
+            max_retries: 3,
+        }
+    }
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        StatsdConfig {
+            enabled: false,
+            address: "127.0.0.1:8125".to_string(),
+            prefix: "openmw.".to_string(),
+            dogstatsd: false,
+            tags: vec![],
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            enabled: false,
+            cert: String::new(),
+            key: String::new(),
+            reload_interval: 60,
+            redirect_port: None,
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            public: DEFAULT_PUBLIC.iter().map(|e| e.to_string()).collect(),
+            tokens: vec![],
+            users: vec![],
+        }
+    }
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        ChatConfig {
+            history_size: 200,
+            private_commands: vec!["/msg".to_string(), "/message".to_string()],
+            redact: vec![],
+        }
+    }
+}
+
+impl Default for SseConfig {
+    fn default() -> Self {
+        SseConfig { history_size: 128 }
+    }
+}
+
+impl Default for WebsocketConfig {
+    fn default() -> Self {
+        WebsocketConfig {
+            queue_size: 64,
+            overflow: OverflowPolicy::DropOldest,
+            ping_interval: 15,
+            idle_timeout: 60,
+        }
+    }
+}
+
+impl Default for EndpointConfig {
+    fn default() -> Self {
+        EndpointConfig {
+            info: true,
+            players: true,
+            cells: true,
+            websocket: true,
+            sse: true,
+            chat: true,
+            commands: true,
+            bans: true,
+            metrics: true,
+            www: true,
+        }
+    }
+}
+
+impl Config {
+    /// Loads `nwahttp.toml` from the mod dir (or `$NWAHTTP_CONFIG`) and applies environment overrides
+    pub fn load() -> Config {
+        let path = env::var("NWAHTTP_CONFIG").unwrap_or_else(|_| get_mod_dir() + "/nwahttp.toml");
+
+        let mut config = match std::fs::read_to_string(&path) {
+            Ok(contents) => match toml::from_str::<Config>(&contents) {
+                Ok(config) => {
+                    log_message(LOG_INFO, format!("Loaded config from {}", path).as_str());
+                    config
+                }
+                Err(err) => {
+                    log_message(
+                        LOG_WARN,
+                        format!("Invalid config in {}, using defaults: {}", path, err).as_str(),
+                    );
+                    Config::default()
+                }
+            },
+            Err(_) => Config::default(),
+        };
+
+        config.apply_env();
+        config.validate();
+        config
+    }
+
+    fn apply_env(&mut self) {
+        env_override("NWAHTTP_ADDRESS", &mut self.address);
+        env_override("NWAHTTP_PORT", &mut self.port);
+        env_override("NWAHTTP_TICK_INTERVAL", &mut self.tick_interval);
+        env_override(
+            "NWAHTTP_LOW_FREQUENCY_DIVISOR",
+            &mut self.low_frequency_divisor,
+        );
+        env_override(
+            "NWAHTTP_MAX_DISTANCE_PER_TICK",
+            &mut self.max_distance_per_tick,
+        );
+        env_override("NWAHTTP_LOGIN_MESSAGE", &mut self.login_message);
+
+        if let Ok(www_dir) = env::var("NWAHTTP_WWW_DIR") {
+            self.www_dir = Some(www_dir);
+        }
+
+        if let Ok(ban_file) = env::var("NWAHTTP_BAN_FILE") {
+            self.ban_file = Some(ban_file);
+        }
+
+        env_override(
+            "NWAHTTP_METRICS_HASH_PLAYER_NAMES",
+            &mut self.metrics.hash_player_names,
+        );
+        env_override("NWAHTTP_METRICS_HASH_SALT", &mut self.metrics.hash_salt);
+        env_override("NWAHTTP_PUSH_ENABLED", &mut self.push.enabled);
+        env_override("NWAHTTP_PUSH_MODE", &mut self.push.mode);
+        env_override("NWAHTTP_PUSH_URL", &mut self.push.url);
+        env_override("NWAHTTP_PUSH_JOB", &mut self.push.job);
+        env_override("NWAHTTP_PUSH_INSTANCE", &mut self.push.instance);
+        env_override("NWAHTTP_PUSH_INTERVAL", &mut self.push.interval);
+        env_override("NWAHTTP_PUSH_MAX_RETRIES", &mut self.push.max_retries);
+        env_override("NWAHTTP_STATSD_ENABLED", &mut self.statsd.enabled);
+        env_override("NWAHTTP_STATSD_ADDRESS", &mut self.statsd.address);
+        env_override("NWAHTTP_STATSD_PREFIX", &mut self.statsd.prefix);
+        env_override("NWAHTTP_STATSD_DOGSTATSD", &mut self.statsd.dogstatsd);
+
+        if let Ok(tags) = env::var("NWAHTTP_STATSD_TAGS") {
+            self.statsd.tags = tags
+                .split(',')
+                .map(|tag| tag.trim().to_string())
+                .filter(|tag| !tag.is_empty())
+                .collect();
+        }
+
+        env_override("NWAHTTP_TLS_ENABLED", &mut self.tls.enabled);
+        env_override("NWAHTTP_TLS_CERT", &mut self.tls.cert);
+        env_override("NWAHTTP_TLS_KEY", &mut self.tls.key);
+        env_override("NWAHTTP_TLS_RELOAD_INTERVAL", &mut self.tls.reload_interval);
+
+        // 0 disables the redirect
+        let mut redirect_port = self.tls.redirect_port.unwrap_or(0);
+        env_override("NWAHTTP_TLS_REDIRECT_PORT", &mut redirect_port);
+        self.tls.redirect_port = Some(redirect_port).filter(|port| *port != 0);
+
+        if let Ok(token) = env::var("NWAHTTP_API_TOKEN") {
+            self.auth.tokens.push(TokenConfig {
+                name: "api".to_string(),
+                token,
+                role: Role::Admin,
+            });
+        }
+
+        if let Ok(public) = env::var("NWAHTTP_AUTH_PUBLIC") {
+            self.auth.public = public
+                .split(',')
+                .map(|endpoint| endpoint.trim().to_string())
+                .filter(|endpoint| !endpoint.is_empty())
+                .collect();
+        }
+
+        env_override("NWAHTTP_ENDPOINT_INFO", &mut self.endpoints.info);
+        env_override("NWAHTTP_ENDPOINT_PLAYERS", &mut self.endpoints.players);
+        env_override("NWAHTTP_ENDPOINT_CELLS", &mut self.endpoints.cells);
+        env_override("NWAHTTP_ENDPOINT_WEBSOCKET", &mut self.endpoints.websocket);
+        env_override("NWAHTTP_ENDPOINT_SSE", &mut self.endpoints.sse);
+        env_override("NWAHTTP_ENDPOINT_CHAT", &mut self.endpoints.chat);
+        env_override("NWAHTTP_ENDPOINT_COMMANDS", &mut self.endpoints.commands);
+        env_override("NWAHTTP_ENDPOINT_BANS", &mut self.endpoints.bans);
+        env_override("NWAHTTP_ENDPOINT_METRICS", &mut self.endpoints.metrics);
+        env_override("NWAHTTP_ENDPOINT_WWW", &mut self.endpoints.www);
+        env_override(
+            "NWAHTTP_WEBSOCKET_QUEUE_SIZE",
+            &mut self.websocket.queue_size,
+        );
+        env_override(
+            "NWAHTTP_WEBSOCKET_PING_INTERVAL",
+            &mut self.websocket.ping_interval,
+        );
+        env_override(
+            "NWAHTTP_WEBSOCKET_IDLE_TIMEOUT",
+            &mut self.websocket.idle_timeout,
+        );
+        env_override("NWAHTTP_SSE_HISTORY_SIZE", &mut self.sse.history_size);
+        env_override("NWAHTTP_CHAT_HISTORY_SIZE", &mut self.chat.history_size);
+        env_override(
+            "NWAHTTP_ANNOUNCE_LEVEL_UP",
+            &mut self.announcements.level_up,
+        );
+        env_override(
+            "NWAHTTP_ANNOUNCE_SKILL_INCREASE",
+            &mut self.announcements.skill_increase,
+        );
+        env_override(
+            "NWAHTTP_ANNOUNCE_ATTRIBUTE_INCREASE",
+            &mut self.announcements.attribute_increase,
+        );
+    }
+
+    fn validate(&mut self) {
+        let defaults = Config::default();
+
+        if IpAddr::from_str(&self.address).is_err() {
+            log_message(
+                LOG_WARN,
+                format!(
+                    "Invalid listen address {:?}, falling back to {}",
+                    self.address, defaults.address
+                )
+                .as_str(),
+            );
+            self.address = defaults.address;
+        }
+
+        if self.tls.enabled && (self.tls.cert.is_empty() || self.tls.key.is_empty()) {
+            log_message(
+                LOG_WARN,
+                "tls.cert and tls.key are required for TLS, falling back to HTTP",
+            );
+            self.tls.enabled = false;
+        }
+
+        if self.tls.reload_interval == 0 {
+            log_message(
+                LOG_WARN,
+                format!(
+                    "tls.reload_interval can't be 0, falling back to {}",
+                    defaults.tls.reload_interval
+                )
+                .as_str(),
+            );
+            self.tls.reload_interval = defaults.tls.reload_interval;
+        }
+
+        if self.tls.redirect_port == Some(self.port) {
+            log_message(
+                LOG_WARN,
+                "tls.redirect_port can't be the same as port, disabling the redirect",
+            );
+            self.tls.redirect_port = None;
+        }
+
+        if self.push.enabled && self.push.url.is_empty() {
+            log_message(
+                LOG_WARN,
+                "push.url is required for pushing metrics, disabling it",
+            );
+            self.push.enabled = false;
+        }
+
+        if self.push.enabled
+            && !self.push.url.starts_with("http://")
+            && !self.push.url.starts_with("https://")
+        {
+            log_message(
+                LOG_WARN,
+                format!(
+                    "push.url {:?} has to start with http:// or https://, disabling pushing metrics",
+                    self.push.url
+                )
+                .as_str(),
+            );
+            self.push.enabled = false;
+        }
+
+        if self.push.job.is_empty() {
+            log_message(
+                LOG_WARN,
+                format!(
+                    "push.job can't be empty, falling back to {}",
+                    defaults.push.job
+                )
+                .as_str(),
+            );
+            self.push.job = defaults.push.job;
+        }
+
+        if self.push.instance.is_empty() {
+            self.push.instance = env::var("HOSTNAME").unwrap_or_else(|_| "tes3mp".to_string());
+        }
+
+        if self.push.interval == 0 {
+            log_message(
+                LOG_WARN,
+                format!(
+                    "push.interval can't be 0, falling back to {}",
+                    defaults.push.interval
+                )
+                .as_str(),
+            );
+            self.push.interval = defaults.push.interval;
+        }
+
+        if !self.statsd.tags.is_empty() && !self.statsd.dogstatsd {
+            log_message(
+                LOG_WARN,
+                "statsd.tags are only sent with statsd.dogstatsd, ignoring them",
+            );
+        }
+
+        self.auth.public.retain(|endpoint| {
+            let known = READ_ENDPOINTS.contains(&endpoint.as_str());
+            if !known {
+                log_message(
+                    LOG_WARN,
+                    format!("Ignoring unknown public endpoint {:?}", endpoint).as_str(),
+                );
+            }
+
+            known
+        });
+
+        self.auth.tokens.retain(|token| {
+            if token.token.is_empty() {
+                log_message(
+                    LOG_WARN,
+                    format!("Ignoring empty auth token {:?}", token.name).as_str(),
+                );
+            }
+
+            !token.token.is_empty()
+        });
+
+        self.auth.users.retain(|user| {
+            if user.password.is_empty() {
+                log_message(
+                    LOG_WARN,
+                    format!("Ignoring auth user {:?} without a password", user.name).as_str(),
+                );
+            }
+
+            !user.password.is_empty()
+        });
+
+        if self.tick_interval == 0 {
+            log_message(
+                LOG_WARN,
+                format!(
+                    "tick_interval can't be 0, falling back to {}",
+                    defaults.tick_interval
+                )
+                .as_str(),
+            );
+            self.tick_interval = defaults.tick_interval;
+        }
+
+        if self.low_frequency_divisor == 0 {
+            log_message(
+                LOG_WARN,
+                format!(
+                    "low_frequency_divisor can't be 0, falling back to {}",
+                    defaults.low_frequency_divisor
+                )
+                .as_str(),
+            );
+            self.low_frequency_divisor = defaults.low_frequency_divisor;
+        }
+
+        if self.max_distance_per_tick.is_nan() || self.max_distance_per_tick <= 0.0 {
+            log_message(
+                LOG_WARN,
+                format!(
+                    "max_distance_per_tick has to be positive, falling back to {}",
+                    defaults.max_distance_per_tick
+                )
+                .as_str(),
+            );
+            self.max_distance_per_tick = defaults.max_distance_per_tick;
+        }
+
+        if self.websocket.queue_size == 0 {
+            log_message(
+                LOG_WARN,
+                format!(
+                    "websocket.queue_size can't be 0, falling back to {}",
+                    defaults.websocket.queue_size
+                )
+                .as_str(),
+            );
+            self.websocket.queue_size = defaults.websocket.queue_size;
+        }
+
+        if self.chat.history_size == 0 {
+            log_message(
+                LOG_WARN,
+                format!(
+                    "chat.history_size can't be 0, falling back to {}",
+                    defaults.chat.history_size
+                )
+                .as_str(),
+            );
+            self.chat.history_size = defaults.chat.history_size;
+        }
+
+        self.chat
+            .redact
+            .retain(|pattern| match Regex::new(pattern) {
+                Ok(_) => true,
+                Err(err) => {
+                    log_message(
+                        LOG_WARN,
+                        format!(
+                            "Ignoring invalid chat.redact pattern {:?}: {}",
+                            pattern, err
+                        )
+                        .as_str(),
+                    );
+                    false
+                }
+            });
+
+        if self.websocket.ping_interval == 0
+            || self.websocket.idle_timeout <= self.websocket.ping_interval
+        {
+            log_message(
+                LOG_WARN,
+                format!(
+                    "websocket.idle_timeout has to be longer than a non-zero websocket.ping_interval, falling back to {}s and {}s",
+                    defaults.websocket.ping_interval, defaults.websocket.idle_timeout
+                )
+                .as_str(),
+            );
+            self.websocket.ping_interval = defaults.websocket.ping_interval;
+            self.websocket.idle_timeout = defaults.websocket.idle_timeout;
+        }
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        SocketAddr::new(
+            IpAddr::from_str(&self.address).expect("Invalid listen argument"),
+            self.port,
+        )
+    }
+
+    pub fn www_dir(&self) -> String {
+        self.www_dir
+            .clone()
+            .unwrap_or_else(|| get_mod_dir() + "/../www")
+    }
+
+    pub fn ban_file(&self) -> String {
+        self.ban_file
+            .clone()
+            .unwrap_or_else(|| get_mod_dir() + "/nwahttp_bans.json")
+    }
+}
+
+fn env_override<T: FromStr>(name: &str, target: &mut T)
+where
+    T::Err: Display,
+{
+    if let Ok(value) = env::var(name) {
+        match value.parse() {
+            Ok(value) => *target = value,
+            Err(err) => log_message(
+                LOG_WARN,
+                format!("Ignoring invalid value for {}: {}", name, err).as_str(),
+            ),
+        }
+    }
+}
@@ -1,34 +1,73 @@
-use crate::plugin::{create_timer, get_mod_dir, log_message, start_timer, Events, LOG_INFO};
+use crate::config::CONFIG;
+use crate::plugin::{create_timer, get_mod_dir, kick, log_message, start_timer, Events, LOG_INFO};
 use crate::server::main_http_thread;
-use crate::server_info::ServerInfoHandle;
+use crate::server_info::{ServerInfoHandle, TICK_DRIFT, TICK_DURATION, UPDATE_PLAYERS_DURATION};
 
 use std::os::raw::{c_int, c_ulonglong, c_ushort};
 use std::rc::Rc;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
 use warp::Future;
 
+mod auth;
+mod config;
 mod plugin;
+mod push;
+mod query;
 mod server;
 mod server_info;
+mod statsd;
+mod tls;
 
 #[derive(Clone)]
 struct ServerHandle(Arc<RwLock<Server>>, Rc<RwLock<Runtime>>);
 
 extern "C" fn tick() -> c_ulonglong {
+    let started = Instant::now();
     let server_handle: &mut ServerHandle = unsafe { EVENTS_INSTANCE.as_mut() }.unwrap();
-    server_handle.clone().with(|server| {
+    let kicks = server_handle.clone().with(|server| {
+        if let Some(timer_started) = server.timer_started {
+            let interval = Duration::from_millis(CONFIG.tick_interval as u64);
+            let waited = started.duration_since(timer_started);
+            let drift = waited.as_secs_f64() - interval.as_secs_f64();
+            TICK_DRIFT.observe(drift);
+            statsd::timing("tick.drift", drift, &[]);
+        }
+
         let timer = server.timer;
         server.tick += 1;
-        server_handle.block_on(async {
-            server.info.update_players(server.tick % 20 == 0).await;
+        let kicks = server_handle.block_on(async {
+            let kicks = server.info.run_commands().await;
+
+            let update_started = Instant::now();
+            server
+                .info
+                .update_players(server.tick % CONFIG.low_frequency_divisor == 0)
+                .await;
+            let update_duration = update_started.elapsed().as_secs_f64();
+            UPDATE_PLAYERS_DURATION.observe(update_duration);
+            statsd::timing("update_players.duration", update_duration, &[]);
+            kicks
         });
 
-        server.tick %= 1000;
+        server.tick %= CONFIG.low_frequency_divisor;
 
-        start_timer(timer)
+        start_timer(timer);
+        server.timer_started = Some(Instant::now());
+        kicks
     });
 
+    // Kicking can call back into the plugin, which needs the locks released
+    for player_id in kicks {
+        log_message(LOG_INFO, format!("Kicking player {}", player_id).as_str());
+        kick(player_id);
+    }
+
+    let duration = started.elapsed().as_secs_f64();
+    TICK_DURATION.observe(duration);
+    statsd::timing("tick.duration", duration, &[]);
+
     0
 }
 
@@ -48,6 +87,8 @@ struct Server {
     info: ServerInfoHandle,
     timer: c_int,
     tick: u64,
+    /// When the timer was last started, used to measure how late ticks are
+    timer_started: Option<Instant>,
 }
 
 impl Server {
@@ -62,6 +103,7 @@ impl Events for ServerHandle {
             info: ServerInfoHandle::new(),
             timer: -1,
             tick: 0,
+            timer_started: None,
         }
         .into_handle(Rc::new(RwLock::new(
             Runtime::new().expect("Failed to create Tokio runtime"),
@@ -86,7 +128,46 @@ impl Events for ServerHandle {
         });
     }
 
+    fn on_player_end_char_gen(&mut self, player_id: c_ushort) {
+        self.clone().with(|server| {
+            self.block_on(async {
+                server.info.enter_game(player_id).await;
+            })
+        })
+    }
+
+    fn on_player_cell_change(&mut self, player_id: c_ushort) {
+        // Existing characters don't go through character generation, their first cell change marks the login
+        self.clone().with(|server| {
+            self.block_on(async {
+                server.info.enter_game(player_id).await;
+            })
+        })
+    }
+
+    fn on_player_death(&mut self, player_id: c_ushort) {
+        self.clone().with(|server| {
+            self.block_on(async {
+                server.info.player_death(player_id).await;
+            })
+        })
+    }
+
+    fn on_player_send_message(&mut self, player_id: c_ushort, message: Option<&str>) {
+        self.clone().with(|server| {
+            self.block_on(async {
+                server.info.chat_message(player_id, message).await;
+            })
+        })
+    }
+
     fn on_player_connect(&mut self, player_id: c_ushort) {
+        // Banned players are kicked without holding the server lock, kicking can call back into the plugin
+        let info = self.with(|server| server.info.clone());
+        if info.enforce_ban(player_id) {
+            return;
+        }
+
         self.clone().with(|server| -> () {
             self.block_on(async {
                 server.info.add_player(player_id).await;
@@ -118,17 +199,20 @@ impl Events for ServerHandle {
             )
             .as_str(),
         );
+        lazy_static::initialize(&CONFIG);
+        statsd::init();
     }
 
     fn on_server_post_init(&mut self) {
         let mut info = {
             self.with(|server| {
-                server.timer = create_timer(tick, 50);
+                server.timer = create_timer(tick, CONFIG.tick_interval as c_int);
                 log_message(
                     LOG_INFO,
                     format!("nwahttp tick timer registered with id {}", server.timer).as_str(),
                 );
                 start_timer(server.timer);
+                server.info.load_bans();
                 server.info.clone()
             })
         };
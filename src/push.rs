@@ -0,0 +1,493 @@
+use crate::config::{PushConfig, CONFIG};
+use crate::plugin::{log_message, LOG_INFO, LOG_WARN};
+use crate::server_info::PUSH_FAILURES;
+use crate::statsd;
+use hyper::client::HttpConnector;
+use hyper::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use prometheus::proto::{MetricFamily, MetricType};
+use prometheus::{Encoder, TextEncoder};
+use serde::Deserialize;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{delay_for, interval};
+
+/// Where `push_metrics` sends metrics to
+#[derive(Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PushMode {
+    /// A Prometheus Pushgateway, `url` is the gateway without the `/metrics/job/...` path
+    Pushgateway,
+    /// A Prometheus remote-write receiver, `url` is the full receive endpoint
+    RemoteWrite,
+}
+
+impl FromStr for PushMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "pushgateway" => Ok(PushMode::Pushgateway),
+            "remote_write" => Ok(PushMode::RemoteWrite),
+            _ => Err(format!("unknown push mode {:?}", mode)),
+        }
+    }
+}
+
+/// Periodically pushes every registered metric to the configured Pushgateway or remote-write endpoint
+pub async fn push_metrics() {
+    let push = &CONFIG.push;
+    // Speaks both http and https, trusting the bundled Mozilla root certificates
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+    let mut ticks = interval(Duration::from_secs(push.interval));
+
+    log_message(
+        LOG_INFO,
+        format!("Pushing metrics to {} every {}s", push.url, push.interval).as_str(),
+    );
+
+    loop {
+        ticks.tick().await;
+
+        let mut attempt = 0;
+        loop {
+            let err = match push_once(&client, push).await {
+                Ok(()) => break,
+                Err(err) => err,
+            };
+
+            PUSH_FAILURES.inc();
+            statsd::count("metrics.push_failures", 1.0, &[]);
+            if attempt >= push.max_retries {
+                log_message(
+                    LOG_WARN,
+                    format!(
+                        "Pushing metrics failed, giving up until the next push: {}",
+                        err
+                    )
+                    .as_str(),
+                );
+                break;
+            }
+
+            // 1s, 2s, 4s, ... but never past the next push
+            let backoff =
+                Duration::from_secs(1 << attempt.min(16)).min(Duration::from_secs(push.interval));
+            log_message(
+                LOG_WARN,
+                format!("Pushing metrics failed, retrying in {:?}: {}", backoff, err).as_str(),
+            );
+            delay_for(backoff).await;
+            attempt += 1;
+        }
+    }
+}
+
+async fn push_once(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    push: &PushConfig,
+) -> Result<(), String> {
+    match client.request(build_request(push)?).await {
+        Ok(response) if response.status().is_success() => Ok(()),
+        Ok(response) => Err(format!("server answered {}", response.status())),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn build_request(push: &PushConfig) -> Result<Request<Body>, String> {
+    let metric_families = prometheus::gather();
+
+    let request = match push.mode {
+        PushMode::Pushgateway => {
+            let encoder = TextEncoder::new();
+            let mut buffer = vec![];
+            encoder
+                .encode(&metric_families, &mut buffer)
+                .map_err(|err| err.to_string())?;
+
+            // PUT replaces the whole group, so series removed here disappear from the gateway too
+            Request::builder()
+                .method(Method::PUT)
+                .uri(format!(
+                    "{}/metrics/job/{}/instance/{}",
+                    push.url.trim_end_matches('/'),
+                    utf8_percent_encode(&push.job, NON_ALPHANUMERIC),
+                    utf8_percent_encode(&push.instance, NON_ALPHANUMERIC)
+                ))
+                .header(CONTENT_TYPE, encoder.format_type())
+                .body(Body::from(buffer))
+        }
+        PushMode::RemoteWrite => {
+            let body = snap::raw::Encoder::new()
+                .compress_vec(&write_request(&metric_families, push))
+                .map_err(|err| err.to_string())?;
+
+            Request::builder()
+                .method(Method::POST)
+                .uri(push.url.as_str())
+                .header(CONTENT_TYPE, "application/x-protobuf")
+                .header(CONTENT_ENCODING, "snappy")
+                .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+                .body(Body::from(body))
+        }
+    };
+
+    request.map_err(|err| err.to_string())
+}
+
+/// A single remote-write time series with one sample
+struct Series {
+    labels: Vec<(String, String)>,
+    value: f64,
+}
+
+impl Series {
+    fn new(name: String, labels: &[(String, String)], value: f64) -> Series {
+        let mut labels = labels.to_vec();
+        labels.push(("__name__".to_string(), name));
+
+        Series { labels, value }
+    }
+
+    fn with_label(
+        name: String,
+        labels: &[(String, String)],
+        label: (&str, String),
+        value: f64,
+    ) -> Series {
+        let mut labels = labels.to_vec();
+        labels.push((label.0.to_string(), label.1));
+        Series::new(name, &labels, value)
+    }
+}
+
+/// Flattens the metric families the way the text format does, histograms and summaries become several series
+fn series(families: &[MetricFamily]) -> Vec<Series> {
+    let mut series = vec![];
+
+    for family in families {
+        let name = family.get_name();
+        for metric in family.get_metric() {
+            let labels: Vec<(String, String)> = metric
+                .get_label()
+                .iter()
+                .map(|label| (label.get_name().to_string(), label.get_value().to_string()))
+                .collect();
+
+            match family.get_field_type() {
+                MetricType::COUNTER => series.push(Series::new(
+                    name.to_string(),
+                    &labels,
+                    metric.get_counter().get_value(),
+                )),
+                MetricType::GAUGE => series.push(Series::new(
+                    name.to_string(),
+                    &labels,
+                    metric.get_gauge().get_value(),
+                )),
+                MetricType::UNTYPED => series.push(Series::new(
+                    name.to_string(),
+                    &labels,
+                    metric.get_untyped().get_value(),
+                )),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    for bucket in histogram.get_bucket() {
+                        series.push(Series::with_label(
+                            format!("{}_bucket", name),
+                            &labels,
+                            ("le", bucket.get_upper_bound().to_string()),
+                            bucket.get_cumulative_count() as f64,
+                        ));
+                    }
+
+                    series.push(Series::with_label(
+                        format!("{}_bucket", name),
+                        &labels,
+                        ("le", "+Inf".to_string()),
+                        histogram.get_sample_count() as f64,
+                    ));
+                    series.push(Series::new(
+                        format!("{}_sum", name),
+                        &labels,
+                        histogram.get_sample_sum(),
+                    ));
+                    series.push(Series::new(
+                        format!("{}_count", name),
+                        &labels,
+                        histogram.get_sample_count() as f64,
+                    ));
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        series.push(Series::with_label(
+                            name.to_string(),
+                            &labels,
+                            ("quantile", quantile.get_quantile().to_string()),
+                            quantile.get_value(),
+                        ));
+                    }
+
+                    series.push(Series::new(
+                        format!("{}_sum", name),
+                        &labels,
+                        summary.get_sample_sum(),
+                    ));
+                    series.push(Series::new(
+                        format!("{}_count", name),
+                        &labels,
+                        summary.get_sample_count() as f64,
+                    ));
+                }
+            }
+        }
+    }
+
+    series
+}
+
+fn varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+
+    buffer.push(value as u8);
+}
+
+/// Writes a length-delimited protobuf field
+fn bytes_field(buffer: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    varint(buffer, field << 3 | 2);
+    varint(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+/// Encodes a remote-write `WriteRequest` protobuf message
+fn write_request(families: &[MetricFamily], push: &PushConfig) -> Vec<u8> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+
+    let mut request = vec![];
+    for series in series(families) {
+        // Remote-write receivers expect the labels sorted by name
+        let mut labels = series.labels;
+        labels.push(("job".to_string(), push.job.clone()));
+        labels.push(("instance".to_string(), push.instance.clone()));
+        labels.sort();
+
+        let mut time_series = vec![];
+        for (name, value) in &labels {
+            let mut label = vec![];
+            bytes_field(&mut label, 1, name.as_bytes());
+            bytes_field(&mut label, 2, value.as_bytes());
+            bytes_field(&mut time_series, 1, &label);
+        }
+
+        let mut sample = vec![];
+        varint(&mut sample, 1 << 3 | 1);
+        sample.extend_from_slice(&series.value.to_le_bytes());
+        varint(&mut sample, 2 << 3);
+        varint(&mut sample, timestamp);
+        bytes_field(&mut time_series, 2, &sample);
+
+        bytes_field(&mut request, 1, &time_series);
+    }
+
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::to_bytes;
+    use hyper::http::request::Parts;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server, StatusCode};
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use tokio::sync::mpsc;
+
+    /// Starts a server that answers every request with `status` and hands the requests to the test
+    fn receiver(status: StatusCode) -> (String, mpsc::UnboundedReceiver<(Parts, Vec<u8>)>) {
+        let (sender, requests) = mpsc::unbounded_channel();
+        let make_service = make_service_fn(move |_| {
+            let sender = sender.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let sender = sender.clone();
+                    async move {
+                        let (parts, body) = request.into_parts();
+                        let body = to_bytes(body).await.unwrap().to_vec();
+                        let _ = sender.send((parts, body));
+                        let mut response = Response::new(Body::empty());
+                        *response.status_mut() = status;
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (url, requests)
+    }
+
+    fn config(mode: PushMode, url: String) -> PushConfig {
+        PushConfig {
+            enabled: true,
+            mode,
+            url,
+            job: "tes3mp".to_string(),
+            instance: "test host".to_string(),
+            ..PushConfig::default()
+        }
+    }
+
+    fn client() -> Client<HttpsConnector<HttpConnector>> {
+        Client::builder().build(HttpsConnector::new())
+    }
+
+    /// Reads a varint, returns it with the amount of bytes read
+    fn read_varint(bytes: &[u8]) -> (u64, usize) {
+        let mut value = 0;
+        for (i, byte) in bytes.iter().enumerate() {
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                return (value, i + 1);
+            }
+        }
+
+        panic!("truncated varint");
+    }
+
+    /// Splits a protobuf message into its length-delimited fields, skipping the others
+    fn read_fields(mut bytes: &[u8]) -> Vec<(u64, &[u8])> {
+        let mut fields = vec![];
+        while !bytes.is_empty() {
+            let (key, read) = read_varint(bytes);
+            bytes = &bytes[read..];
+            match key & 7 {
+                0 => bytes = &bytes[read_varint(bytes).1..],
+                1 => bytes = &bytes[8..],
+                2 => {
+                    let (len, read) = read_varint(bytes);
+                    let end = read + len as usize;
+                    fields.push((key >> 3, &bytes[read..end]));
+                    bytes = &bytes[end..];
+                }
+                wire_type => panic!("unexpected wire type {}", wire_type),
+            }
+        }
+
+        fields
+    }
+
+    /// Decodes the label sets of a remote-write `WriteRequest`
+    fn read_write_request(bytes: &[u8]) -> Vec<Vec<(String, String)>> {
+        read_fields(bytes)
+            .into_iter()
+            .map(|(field, time_series)| {
+                assert_eq!(field, 1);
+                read_fields(time_series)
+                    .into_iter()
+                    .filter(|(field, _)| *field == 1)
+                    .map(|(_, label)| {
+                        let label = read_fields(label);
+                        (
+                            String::from_utf8(label[0].1.to_vec()).unwrap(),
+                            String::from_utf8(label[1].1.to_vec()).unwrap(),
+                        )
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn encodes_varints() {
+        let mut buffer = vec![];
+        varint(&mut buffer, 1);
+        varint(&mut buffer, 300);
+        assert_eq!(buffer, vec![1, 0xac, 0x02]);
+        assert_eq!(read_varint(&buffer[1..]), (300, 2));
+    }
+
+    #[tokio::test]
+    async fn pushes_text_format_to_the_pushgateway() {
+        lazy_static::initialize(&PUSH_FAILURES);
+        let (url, mut requests) = receiver(StatusCode::OK);
+
+        push_once(&client(), &config(PushMode::Pushgateway, url + "/"))
+            .await
+            .unwrap();
+
+        let (parts, body) = requests.recv().await.unwrap();
+        assert_eq!(parts.method, Method::PUT);
+        assert_eq!(parts.uri.path(), "/metrics/job/tes3mp/instance/test%20host");
+        assert_eq!(
+            parts.headers[CONTENT_TYPE],
+            TextEncoder::new().format_type()
+        );
+
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("# TYPE openmw_metrics_push_failures_total counter"));
+    }
+
+    #[tokio::test]
+    async fn pushes_snappy_protobuf_to_remote_write() {
+        lazy_static::initialize(&PUSH_FAILURES);
+        let (url, mut requests) = receiver(StatusCode::OK);
+
+        push_once(
+            &client(),
+            &config(PushMode::RemoteWrite, url + "/api/v1/write"),
+        )
+        .await
+        .unwrap();
+
+        let (parts, body) = requests.recv().await.unwrap();
+        assert_eq!(parts.method, Method::POST);
+        assert_eq!(parts.uri.path(), "/api/v1/write");
+        assert_eq!(parts.headers[CONTENT_TYPE], "application/x-protobuf");
+        assert_eq!(parts.headers[CONTENT_ENCODING], "snappy");
+        assert_eq!(parts.headers["X-Prometheus-Remote-Write-Version"], "0.1.0");
+
+        let body = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        let series = read_write_request(&body);
+        let failures = series
+            .iter()
+            .find(|labels| {
+                labels.contains(&(
+                    "__name__".to_string(),
+                    "openmw_metrics_push_failures_total".to_string(),
+                ))
+            })
+            .unwrap();
+
+        let names: Vec<&str> = failures.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["__name__", "instance", "job"]);
+        let labels: HashMap<&str, &str> = failures
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(labels["job"], "tes3mp");
+        assert_eq!(labels["instance"], "test host");
+    }
+
+    #[tokio::test]
+    async fn fails_on_error_responses() {
+        let (url, _requests) = receiver(StatusCode::SERVICE_UNAVAILABLE);
+
+        let result = push_once(&client(), &config(PushMode::Pushgateway, url)).await;
+        assert_eq!(
+            result,
+            Err("server answered 503 Service Unavailable".to_string())
+        );
+    }
+}
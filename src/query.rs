@@ -0,0 +1,214 @@
+use crate::server_info::{Player, PlayerState};
+use serde::Deserialize;
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::os::raw::c_int;
+
+#[derive(Deserialize, Debug, Clone, Copy, Eq, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Query parameters accepted by `/api/players`
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerQuery {
+    pub cell: Option<String>,
+    pub outside: Option<bool>,
+    pub logged_in: Option<bool>,
+    pub state: Option<PlayerState>,
+    pub min_level: Option<c_int>,
+    pub fields: Option<String>,
+    pub sort: Option<String>,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+impl PlayerQuery {
+    pub fn matches(&self, player: &Player) -> bool {
+        if let Some(cell) = &self.cell {
+            if !player.cell.eq_ignore_ascii_case(cell) {
+                return false;
+            }
+        }
+
+        if let Some(outside) = self.outside {
+            if player.is_outside != outside {
+                return false;
+            }
+        }
+
+        if let Some(logged_in) = self.logged_in {
+            if player.logged_in != logged_in {
+                return false;
+            }
+        }
+
+        if let Some(state) = self.state {
+            if player.state != state {
+                return false;
+            }
+        }
+
+        if let Some(min_level) = self.min_level {
+            if player.level < min_level {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Filters, sorts and strips the players according to the query
+    pub fn apply(&self, players: Vec<Player>) -> Vec<Value> {
+        let mut values: Vec<Value> = players
+            .iter()
+            .filter(|p| self.matches(p))
+            .map(|p| serde_json::to_value(p).unwrap())
+            .collect();
+
+        if let Some(sort) = &self.sort {
+            let sort = sort.as_str();
+            values.sort_by(|a, b| match self.order {
+                SortOrder::Asc => compare_values(&a[sort], &b[sort]),
+                SortOrder::Desc => compare_values(&b[sort], &a[sort]),
+            });
+        }
+
+        if let Some(fields) = &self.fields {
+            let fields: Vec<&str> = fields
+                .split(',')
+                .map(|f| f.trim())
+                .filter(|f| !f.is_empty())
+                .collect();
+
+            for value in &mut values {
+                if let Value::Object(map) = value {
+                    let unwanted: Vec<String> = map
+                        .keys()
+                        .filter(|key| !fields.contains(&key.as_str()))
+                        .cloned()
+                        .collect();
+
+                    for key in unwanted {
+                        map.remove(&key);
+                    }
+                }
+            }
+        }
+
+        values
+    }
+}
+
+fn compare_values(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Value::String(a), Value::String(b)) => a.to_lowercase().cmp(&b.to_lowercase()),
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Null, Value::Null) => Ordering::Equal,
+        (Value::Null, _) => Ordering::Greater,
+        (_, Value::Null) => Ordering::Less,
+        _ => Ordering::Equal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: &str) -> Result<PlayerQuery, serde_urlencoded::de::Error> {
+        serde_urlencoded::from_str(query)
+    }
+
+    fn player(name: &str, cell: &str, level: c_int, state: PlayerState) -> Player {
+        let mut player = Player {
+            name: name.to_string(),
+            cell: cell.to_string(),
+            level,
+            ..Player::default()
+        };
+        player.set_state(state);
+        player
+    }
+
+    #[test]
+    fn parses_valid_parameters() {
+        let query =
+            parse("cell=Balmora&outside=false&loggedIn=true&state=inGame&minLevel=5&sort=level&order=desc&fields=name,level")
+                .unwrap();
+
+        assert_eq!(query.cell.as_deref(), Some("Balmora"));
+        assert_eq!(query.outside, Some(false));
+        assert_eq!(query.logged_in, Some(true));
+        assert_eq!(query.state, Some(PlayerState::InGame));
+        assert_eq!(query.min_level, Some(5));
+        assert_eq!(query.sort.as_deref(), Some("level"));
+        assert_eq!(query.order, SortOrder::Desc);
+        assert_eq!(query.fields.as_deref(), Some("name,level"));
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        assert!(parse("loggedIn=maybe").is_err());
+        assert!(parse("outside=1").is_err());
+        assert!(parse("minLevel=high").is_err());
+        assert!(parse("state=flying").is_err());
+        assert!(parse("order=sideways").is_err());
+    }
+
+    #[test]
+    fn missing_parameters_match_everyone() {
+        let query = parse("").unwrap();
+        assert!(query.cell.is_none());
+        assert!(query.logged_in.is_none());
+        assert_eq!(query.order, SortOrder::Asc);
+
+        assert!(query.matches(&player("Fargoth", "Seyda Neen", 1, PlayerState::Connected)));
+        assert!(query.matches(&player("Vivec", "Vivec", 99, PlayerState::InGame)));
+    }
+
+    #[test]
+    fn filters_players() {
+        let fargoth = player("Fargoth", "Seyda Neen", 1, PlayerState::Connected);
+        let vivec = player("Vivec", "Vivec", 99, PlayerState::InGame);
+
+        let logged_in = parse("loggedIn=true").unwrap();
+        assert!(!logged_in.matches(&fargoth));
+        assert!(logged_in.matches(&vivec));
+
+        let cell = parse("cell=seyda%20neen").unwrap();
+        assert!(cell.matches(&fargoth));
+        assert!(!cell.matches(&vivec));
+
+        let min_level = parse("minLevel=2").unwrap();
+        assert!(!min_level.matches(&fargoth));
+        assert!(min_level.matches(&vivec));
+    }
+
+    #[test]
+    fn sorts_and_strips_fields() {
+        let players = vec![
+            player("Fargoth", "Seyda Neen", 1, PlayerState::InGame),
+            player("Vivec", "Vivec", 99, PlayerState::InGame),
+        ];
+
+        let values = parse("sort=level&order=desc&fields=name")
+            .unwrap()
+            .apply(players);
+
+        assert_eq!(
+            values,
+            vec![
+                serde_json::json!({"name": "Vivec"}),
+                serde_json::json!({"name": "Fargoth"}),
+            ]
+        );
+    }
+}
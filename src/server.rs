@@ -1,15 +1,36 @@
+use crate::auth::{require, Forbidden, Identity, Role, Unauthorized};
+use crate::config::CONFIG;
 use crate::plugin::*;
-use crate::server_info::{Player, ServerInfoHandle};
-use hyper::{header::CONTENT_TYPE, Body, Response};
+use crate::push::push_metrics;
+use crate::query::PlayerQuery;
+use crate::server_info::{
+    AdminCommand, CommandError, CommandResult, NewBan, OutgoingMessage, ServerInfoHandle,
+    Subscription, HTTP_REQUESTS, HTTP_REQUEST_DURATION,
+};
+use crate::statsd::Batch;
+use crate::tls::{redirect_http, serve_tls};
+use futures_util::StreamExt;
+use hyper::{
+    header::{CONTENT_TYPE, WWW_AUTHENTICATE},
+    Body, Response, StatusCode,
+};
 use lazy_static::lazy_static;
+use percent_encoding::percent_decode_str;
 use prometheus::{Encoder, TextEncoder};
-use std::{net::SocketAddr, str::FromStr};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::os::raw::c_ushort;
 use warp;
+use warp::filters::fs::File;
+use warp::filters::path::Tail;
+use warp::reply::{Json, WithStatus};
 use warp::ws::Ws;
-use warp::{filters::path::end, Filter};
+use warp::{filters::path::end, Filter, Rejection, Reply};
 
 lazy_static! {
-    static ref SERVER_VERSION: String = get_server_version();
+    pub static ref SERVER_VERSION: String = get_server_version();
 }
 
 fn get_info() -> String {
@@ -31,64 +52,543 @@ fn get_info() -> String {
     )
 }
 
-async fn list_players(info: ServerInfoHandle) -> Vec<Player> {
-    info.get_players().await
+/// Rejects with a 404 when the endpoint is disabled in the config
+fn enabled(flag: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            if flag {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+#[derive(Deserialize, Default)]
+struct SseQuery {
+    topics: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct ChatQuery {
+    #[serde(default)]
+    since: u64,
+}
+
+#[derive(Serialize)]
+struct CommandReply {
+    ok: bool,
+}
+
+#[derive(Serialize)]
+struct ApiError<'a> {
+    error: &'a str,
+}
+
+fn json_reply<T: Serialize>(value: &T) -> WithStatus<Json> {
+    warp::reply::with_status(warp::reply::json(value), StatusCode::OK)
+}
+
+fn json_error(status: StatusCode, error: &str) -> WithStatus<Json> {
+    warp::reply::with_status(warp::reply::json(&ApiError { error }), status)
+}
+
+fn command_reply(result: CommandResult) -> WithStatus<Json> {
+    let status = match &result {
+        Ok(()) => return json_reply(&CommandReply { ok: true }),
+        Err(CommandError::PlayerNotFound) => StatusCode::NOT_FOUND,
+        Err(CommandError::NobodyOnline) => StatusCode::CONFLICT,
+        Err(CommandError::Invalid(_)) => StatusCode::BAD_REQUEST,
+        Err(CommandError::Timeout) => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    json_error(status, &result.unwrap_err().to_string())
+}
+
+async fn run_command(
+    info: ServerInfoHandle,
+    identity: Identity,
+    command: AdminCommand,
+) -> Result<WithStatus<Json>, Rejection> {
+    log_message(
+        LOG_INFO,
+        format!("{} runs {:?}", identity.name, command).as_str(),
+    );
+
+    Ok(command_reply(info.execute(command).await))
+}
+
+async fn add_ban(
+    info: ServerInfoHandle,
+    identity: Identity,
+    mut ban: NewBan,
+) -> Result<WithStatus<Json>, Rejection> {
+    ban.issued_by = Some(identity.name);
+
+    match info.add_ban(ban).await {
+        Ok(ban) => Ok(warp::reply::with_status(
+            warp::reply::json(&ban),
+            StatusCode::CREATED,
+        )),
+        Err(err) => Ok(command_reply(Err(err))),
+    }
+}
+
+async fn remove_ban(
+    info: ServerInfoHandle,
+    id: u64,
+    identity: Identity,
+) -> Result<WithStatus<Json>, Rejection> {
+    match info.remove_ban(id) {
+        Some(ban) => {
+            log_message(
+                LOG_INFO,
+                format!("{} removed ban {}", identity.name, ban.id).as_str(),
+            );
+            Ok(json_reply(&ban))
+        }
+        None => Ok(json_error(StatusCode::NOT_FOUND, "Ban not found")),
+    }
+}
+
+/// Label of requests that didn't match any route
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Groups request paths by route so player and ban ids, or any other path, don't end up in metric labels
+fn route_label(path: &str, status: StatusCode) -> &'static str {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    match segments.as_slice() {
+        ["info"] => "/info",
+        ["api", "players"] => "/api/players",
+        ["api", "players", _, "kick"] => "/api/players/{id}/kick",
+        ["api", "players", ..] => "/api/players/{id}",
+        ["api", "cells"] => "/api/cells",
+        ["api", "chat"] => "/api/chat",
+        ["api", "commands"] => "/api/commands",
+        ["api", "bans"] => "/api/bans",
+        ["api", "bans", _] => "/api/bans/{id}",
+        ["ws", "players"] => "/ws/players",
+        ["sse", "players"] => "/sse/players",
+        ["metrics"] => "/metrics",
+        ["api", ..] | ["ws", ..] | ["sse", ..] => UNMATCHED_ROUTE,
+        // Static files can have any path, only the ones that were found count as www
+        _ if status != StatusCode::NOT_FOUND => "www",
+        _ => UNMATCHED_ROUTE,
+    }
+}
+
+/// Updates the Prometheus request metrics, returns the route the request was counted for
+fn count_request(path: &str, method: &str, status: StatusCode, duration: f64) -> &'static str {
+    let route = route_label(path, status);
+    HTTP_REQUESTS
+        .with_label_values(&[route, method, status.as_str()])
+        .inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[route])
+        .observe(duration);
+
+    route
+}
+
+fn record_request(info: warp::log::Info) {
+    let (method, status) = (info.method().as_str(), info.status());
+    let duration = info.elapsed().as_secs_f64();
+    let route = count_request(info.path(), method, status, duration);
+
+    let mut statsd = Batch::default();
+    statsd.count(
+        "http.requests",
+        1.0,
+        &[
+            ("route", route),
+            ("method", method),
+            ("status", status.as_str()),
+        ],
+    );
+    statsd.timing("http.request_duration", duration, &[("route", route)]);
+}
+
+/// Turns auth rejections into JSON errors, everything else is handled by warp
+async fn handle_rejection(rejection: Rejection) -> Result<warp::reply::Response, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        return Ok(warp::reply::with_header(
+            json_error(StatusCode::UNAUTHORIZED, "Unauthorized"),
+            WWW_AUTHENTICATE,
+            "Basic realm=\"nwahttp\"",
+        )
+        .into_response());
+    }
+
+    if rejection.find::<Forbidden>().is_some() {
+        return Ok(json_error(StatusCode::FORBIDDEN, "Forbidden").into_response());
+    }
+
+    Err(rejection)
+}
+
+async fn list_players(info: ServerInfoHandle, query: PlayerQuery) -> Vec<Value> {
+    query.apply(info.get_players().await)
+}
+
+/// Handles `/api/players/{id}[/resource]` and `/api/players/by-name/{name}[/resource]`
+async fn player_resource(
+    info: ServerInfoHandle,
+    tail: Tail,
+) -> Result<WithStatus<Json>, Rejection> {
+    let segments: Vec<&str> = tail.as_str().split('/').filter(|s| !s.is_empty()).collect();
+
+    // Anything below /api/players is answered here, unknown ids and resources get a JSON 404 too
+    let (player, resource) = match segments.as_slice() {
+        ["by-name", name, resource @ ..] => {
+            let name = percent_decode_str(name).decode_utf8_lossy();
+            (info.get_player_by_name(&name).await, resource)
+        }
+        [id, resource @ ..] => match id.parse::<c_ushort>() {
+            Ok(id) => (info.get_player(id).await, resource),
+            Err(_) => (None, resource),
+        },
+        [] => (None, &[][..]),
+    };
+
+    if resource.len() > 1 {
+        return Ok(json_error(StatusCode::NOT_FOUND, "Resource not found"));
+    }
+
+    let player = match player {
+        Some(player) => player,
+        None => return Ok(json_error(StatusCode::NOT_FOUND, "Player not found")),
+    };
+
+    match resource.first() {
+        None => Ok(json_reply(&player)),
+        Some(&"skills") => Ok(json_reply(&player.skills)),
+        Some(&"attributes") => Ok(json_reply(&player.attributes)),
+        Some(&"position") => Ok(json_reply(&player.get_location())),
+        Some(&"class") => Ok(json_reply(&player.get_class_info())),
+        Some(_) => Ok(json_error(StatusCode::NOT_FOUND, "Resource not found")),
+    }
 }
 
 pub async fn main_http_thread(info: ServerInfoHandle) {
-    let fs = warp::fs::dir(get_mod_dir() + "/../www");
+    let endpoints = &CONFIG.endpoints;
+    let fs = enabled(endpoints.www)
+        .and(require(Role::Viewer, "www"))
+        .and(warp::fs::dir(CONFIG.www_dir()))
+        .map(|_: Identity, file: File| file);
 
-    let index = warp::path("info").and(warp::path::end()).map(|| get_info());
+    let index = enabled(endpoints.info)
+        .and(warp::path("info"))
+        .and(warp::path::end())
+        .and(require(Role::Viewer, "info"))
+        .map(|_: Identity| get_info());
 
     let player_info = info.clone();
-    let players = warp::path("api")
+    let players = enabled(endpoints.players)
+        .and(warp::path("api"))
         .and(warp::path("players"))
         .and(end())
-        .and_then(move || {
+        .and(require(Role::Viewer, "players"))
+        .and(warp::query::<PlayerQuery>())
+        .and_then(move |_: Identity, query: PlayerQuery| {
             let player_info = player_info.clone();
             async move {
-                Ok(warp::reply::json(&list_players(player_info.clone()).await))
-                    as Result<_, warp::Rejection>
+                Ok(warp::reply::json(
+                    &list_players(player_info.clone(), query).await,
+                )) as Result<_, warp::Rejection>
             }
         });
 
+    let player_info = info.clone();
+    let player = enabled(endpoints.players)
+        .and(warp::path("api"))
+        .and(warp::path("players"))
+        .and(warp::path::tail())
+        .and(require(Role::Viewer, "players"))
+        .and_then(move |tail: Tail, _: Identity| player_resource(player_info.clone(), tail));
+
+    let cell_info = info.clone();
+    let cells = enabled(endpoints.cells)
+        .and(warp::path("api"))
+        .and(warp::path("cells"))
+        .and(end())
+        .and(require(Role::Viewer, "cells"))
+        .and_then(move |_: Identity| {
+            let cell_info = cell_info.clone();
+            async move {
+                Ok(warp::reply::json(&cell_info.get_cells().await)) as Result<_, warp::Rejection>
+            }
+        });
+
+    let chat_info = info.clone();
+    let chat = enabled(endpoints.chat)
+        .and(warp::path("api"))
+        .and(warp::path("chat"))
+        .and(end())
+        .and(require(Role::Viewer, "chat"))
+        .and(warp::query::<ChatQuery>())
+        .and_then(move |identity: Identity, query: ChatQuery| {
+            let chat_info = chat_info.clone();
+            async move {
+                Ok(warp::reply::json(
+                    &chat_info.get_chat(query.since, !identity.anonymous).await,
+                )) as Result<_, warp::Rejection>
+            }
+        });
+
+    let chat_info = info.clone();
+    let post_chat = enabled(endpoints.chat)
+        .and(warp::path("api"))
+        .and(warp::path("chat"))
+        .and(end())
+        .and(require(Role::Moderator, "chat"))
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json::<OutgoingMessage>())
+        .and_then(move |identity: Identity, message: OutgoingMessage| {
+            run_command(chat_info.clone(), identity, AdminCommand::Message(message))
+        });
+
+    let command_info = info.clone();
+    let commands = enabled(endpoints.commands)
+        .and(warp::path("api"))
+        .and(warp::path("commands"))
+        .and(end())
+        .and(require(Role::Admin, "commands"))
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json::<AdminCommand>())
+        .and_then(move |identity: Identity, command: AdminCommand| {
+            run_command(command_info.clone(), identity, command)
+        });
+
+    let kick_info = info.clone();
+    let kick = enabled(endpoints.commands)
+        .and(warp::path("api"))
+        .and(warp::path("players"))
+        .and(warp::path::param::<c_ushort>())
+        .and(warp::path("kick"))
+        .and(end())
+        .and(require(Role::Moderator, "kick"))
+        .and_then(move |player_id: c_ushort, identity: Identity| {
+            run_command(
+                kick_info.clone(),
+                identity,
+                AdminCommand::Kick { player_id },
+            )
+        });
+
+    let ban_info = info.clone();
+    let bans = enabled(endpoints.bans)
+        .and(warp::path("api"))
+        .and(warp::path("bans"))
+        .and(end())
+        .and(require(Role::Moderator, "bans"))
+        .and_then(move |_: Identity| {
+            let ban_info = ban_info.clone();
+            async move { Ok(json_reply(&ban_info.get_bans())) as Result<_, warp::Rejection> }
+        });
+
+    let ban_info = info.clone();
+    let post_ban = enabled(endpoints.bans)
+        .and(warp::path("api"))
+        .and(warp::path("bans"))
+        .and(end())
+        .and(require(Role::Moderator, "bans"))
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json::<NewBan>())
+        .and_then(move |identity: Identity, ban: NewBan| add_ban(ban_info.clone(), identity, ban));
+
+    let ban_info = info.clone();
+    let delete_ban = enabled(endpoints.bans)
+        .and(warp::path("api"))
+        .and(warp::path("bans"))
+        .and(warp::path::param::<u64>())
+        .and(end())
+        .and(require(Role::Moderator, "bans"))
+        .and_then(move |id: u64, identity: Identity| remove_ban(ban_info.clone(), id, identity));
+
     let server_info = info.clone();
-    let player_websocket = warp::path("ws")
+    let player_websocket = enabled(endpoints.websocket)
+        .and(warp::path("ws"))
         .and(warp::path("players"))
         .and(warp::path::end())
+        .and(require(Role::Viewer, "websocket"))
         .and(warp::ws())
-        .map(move |ws: Ws| {
-            let server_info = server_info.clone();
-            ws.on_upgrade(move |webs| {
+        .and(warp::addr::remote())
+        .map(
+            move |identity: Identity, ws: Ws, remote: Option<SocketAddr>| {
                 let server_info = server_info.clone();
-                async move {
+                ws.on_upgrade(move |webs| {
                     let server_info = server_info.clone();
-                    server_info.add_websocket(webs).await;
+                    async move {
+                        let server_info = server_info.clone();
+                        let subscription =
+                            Subscription::default().private_chat(!identity.anonymous);
+                        server_info.add_websocket(webs, subscription, remote).await;
+                    }
+                })
+            },
+        );
+
+    let sse_info = info.clone();
+    let player_sse = enabled(endpoints.sse)
+        .and(warp::path("sse"))
+        .and(warp::path("players"))
+        .and(warp::path::end())
+        .and(require(Role::Viewer, "sse"))
+        .and(warp::sse::last_event_id::<u64>())
+        .and(warp::query::<SseQuery>())
+        .and(warp::addr::remote())
+        .and_then(
+            move |identity: Identity,
+                  last_event_id: Option<u64>,
+                  query: SseQuery,
+                  remote: Option<SocketAddr>| {
+                let sse_info = sse_info.clone();
+                async move {
+                    let subscription = query
+                        .topics
+                        .map(|topics| Subscription::from_topics(&topics))
+                        .unwrap_or_default()
+                        .private_chat(!identity.anonymous);
+                    let events = sse_info
+                        .add_sse_client(subscription, last_event_id, remote)
+                        .await
+                        .map(|frame| {
+                            Ok::<_, Infallible>((
+                                warp::sse::id(frame.id),
+                                warp::sse::event(frame.event),
+                                warp::sse::data(frame.json),
+                            ))
+                        });
+
+                    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+                        as Result<_, warp::Rejection>
                 }
-            })
+            },
+        );
+
+    let metrics_endpoint = enabled(endpoints.metrics)
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(require(Role::Viewer, "metrics"))
+        .map(move |_: Identity| {
+            let encoder = TextEncoder::new();
+            let metric_families = prometheus::gather();
+            let mut buffer = vec![];
+            encoder.encode(&metric_families, &mut buffer).unwrap();
+            Response::builder()
+                .status(200)
+                .header(CONTENT_TYPE, encoder.format_type())
+                .body(Body::from(buffer))
+                .unwrap()
         });
 
-    let metrics_endpoint = warp::path("metrics").and(warp::path::end()).map(move || {
-        let encoder = TextEncoder::new();
-        let metric_families = prometheus::gather();
-        let mut buffer = vec![];
-        encoder.encode(&metric_families, &mut buffer).unwrap();
-        Response::builder()
-            .status(200)
-            .header(CONTENT_TYPE, encoder.format_type())
-            .body(Body::from(buffer))
-            .unwrap()
-    });
-
-    let endpoint = warp::get().and(
-        index
-            .or(players)
-            .or(player_websocket)
-            .or(metrics_endpoint)
-            .or(fs),
-    );
+    let endpoint = warp::get()
+        .and(
+            index
+                .or(players)
+                .or(player)
+                .or(cells)
+                .or(chat)
+                .or(bans)
+                .or(player_websocket)
+                .or(player_sse)
+                .or(metrics_endpoint)
+                .or(fs),
+        )
+        .or(warp::post().and(post_chat.or(commands).or(kick).or(post_ban)))
+        .or(warp::delete().and(delete_ban))
+        .recover(handle_rejection)
+        .with(warp::log::custom(record_request))
+        .map(Reply::into_response)
+        .boxed();
+
+    if CONFIG.push.enabled {
+        tokio::spawn(push_metrics());
+    }
+
+    if CONFIG.tls.enabled {
+        if let Some(port) = CONFIG.tls.redirect_port {
+            tokio::spawn(redirect_http(port));
+        }
+
+        serve_tls(endpoint).await
+    } else {
+        warp::serve(endpoint).run(CONFIG.listen_addr()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_routes_without_ids() {
+        let ok = StatusCode::OK;
+        assert_eq!(route_label("/info", ok), "/info");
+        assert_eq!(route_label("/api/players", ok), "/api/players");
+        assert_eq!(route_label("/api/players/3", ok), "/api/players/{id}");
+        assert_eq!(
+            route_label("/api/players/3/location", ok),
+            "/api/players/{id}"
+        );
+        assert_eq!(
+            route_label("/api/players/3/kick", ok),
+            "/api/players/{id}/kick"
+        );
+        assert_eq!(route_label("/api/bans/12", ok), "/api/bans/{id}");
+        assert_eq!(route_label("/ws/players/", ok), "/ws/players");
+        assert_eq!(route_label("/sse/players", ok), "/sse/players");
+        assert_eq!(route_label("/metrics", ok), "/metrics");
+    }
+
+    #[test]
+    fn labels_static_files_as_www() {
+        assert_eq!(route_label("/", StatusCode::OK), "www");
+        assert_eq!(route_label("/js/map.js", StatusCode::OK), "www");
+        assert_eq!(route_label("/index.html", StatusCode::UNAUTHORIZED), "www");
+    }
+
+    #[test]
+    fn labels_unmatched_paths_the_same() {
+        assert_eq!(
+            route_label("/wp-login.php", StatusCode::NOT_FOUND),
+            UNMATCHED_ROUTE
+        );
+        assert_eq!(route_label("/", StatusCode::NOT_FOUND), UNMATCHED_ROUTE);
+        assert_eq!(route_label("/api/unknown", StatusCode::OK), UNMATCHED_ROUTE);
+        assert_eq!(
+            route_label("/api/bans/1/2", StatusCode::NOT_FOUND),
+            UNMATCHED_ROUTE
+        );
+        assert_eq!(route_label("/ws/other", StatusCode::OK), UNMATCHED_ROUTE);
+    }
+
+    #[test]
+    fn counts_requests_by_route() {
+        let requests = |route, status| {
+            HTTP_REQUESTS
+                .with_label_values(&[route, "DELETE", status])
+                .get()
+        };
+        let (bans, unmatched) = (
+            requests("/api/bans/{id}", "200"),
+            requests(UNMATCHED_ROUTE, "404"),
+        );
+
+        let ok = StatusCode::OK;
+        assert_eq!(
+            count_request("/api/bans/1", "DELETE", ok, 0.1),
+            "/api/bans/{id}"
+        );
+        count_request("/api/bans/2", "DELETE", ok, 0.1);
+        for path in &["/.env", "/api/nothing"] {
+            let route = count_request(path, "DELETE", StatusCode::NOT_FOUND, 0.1);
+            assert_eq!(route, UNMATCHED_ROUTE);
+        }
 
-    warp::serve(endpoint)
-        .run(SocketAddr::from_str("[::]:8787").expect("Invalid listen argument"))
-        .await
+        assert_eq!(requests("/api/bans/{id}", "200") - bans, 2);
+        assert_eq!(requests(UNMATCHED_ROUTE, "404") - unmatched, 2);
+    }
 }
@@ -0,0 +1,363 @@
+use crate::config::CONFIG;
+use crate::plugin::{get_ip, get_name, kick, log_message, LOG_INFO, LOG_WARN};
+use crate::server_info::commands::{AdminCommand, CommandError};
+use crate::server_info::events::timestamp;
+use crate::server_info::logic::ServerInfoHandle;
+use serde::{Deserialize, Serialize};
+use std::os::raw::c_ushort;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Ban {
+    pub id: u64,
+    pub name: Option<String>,
+    pub ip: Option<String>,
+    pub reason: String,
+    pub issued_by: String,
+    pub issued_at: u64,
+    /// Unix time after which the ban no longer applies, `None` is permanent
+    pub expires_at: Option<u64>,
+}
+
+impl Ban {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn matches(&self, name: &str, ip: &str) -> bool {
+        self.name
+            .as_ref()
+            .is_some_and(|banned| banned.eq_ignore_ascii_case(name))
+            || self.ip.as_ref().is_some_and(|banned| banned == ip)
+    }
+}
+
+/// What an admin sends to `POST /api/bans`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NewBan {
+    pub name: Option<String>,
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub reason: String,
+    /// Seconds until the ban expires, permanent if missing
+    pub duration: Option<u64>,
+    /// Set from the credentials of the request
+    #[serde(skip)]
+    pub issued_by: Option<String>,
+}
+
+impl NewBan {
+    /// Drops empty names and ips, a ban needs at least one of them and an expiry that fits into a timestamp
+    fn validate(&mut self, now: u64) -> Result<(), CommandError> {
+        self.name = self.name.take().filter(|name| !name.is_empty());
+        self.ip = self.ip.take().filter(|ip| !ip.is_empty());
+        if self.name.is_none() && self.ip.is_none() {
+            return Err(CommandError::Invalid("Expected a name or an ip"));
+        }
+
+        if self
+            .duration
+            .is_some_and(|duration| now.checked_add(duration).is_none())
+        {
+            return Err(CommandError::Invalid("Duration is out of range"));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BanList {
+    next_id: u64,
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    /// Reads the ban list from `CONFIG.ban_file()`, starts empty if there is none
+    pub fn load() -> BanList {
+        let path = CONFIG.ban_file();
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(_) => return BanList::default(),
+        };
+
+        match serde_json::from_str::<BanList>(&contents) {
+            Ok(bans) => {
+                log_message(
+                    LOG_INFO,
+                    format!("Loaded {} bans from {}", bans.bans.len(), path).as_str(),
+                );
+                bans
+            }
+            Err(err) => {
+                log_message(
+                    LOG_WARN,
+                    format!("Invalid ban list in {}, starting empty: {}", path, err).as_str(),
+                );
+                BanList::default()
+            }
+        }
+    }
+
+    fn save(&self) {
+        let path = CONFIG.ban_file();
+        let result = serde_json::to_string_pretty(self)
+            .map_err(|err| err.to_string())
+            .and_then(|json| std::fs::write(&path, json).map_err(|err| err.to_string()));
+
+        if let Err(err) = result {
+            log_message(
+                LOG_WARN,
+                format!("Failed to save ban list to {}: {}", path, err).as_str(),
+            );
+        }
+    }
+
+    /// Drops bans that expired by `now`, returns true if any were removed
+    fn remove_expired(&mut self, now: u64) -> bool {
+        let count = self.bans.len();
+        self.bans.retain(|ban| !ban.is_expired(now));
+
+        self.bans.len() != count
+    }
+
+    /// Drops expired bans, saves the list if any were removed
+    fn prune(&mut self) {
+        if self.remove_expired(timestamp()) {
+            self.save();
+        }
+    }
+
+    pub fn add(&mut self, ban: NewBan) -> Ban {
+        self.next_id += 1;
+        let now = timestamp();
+        let ban = Ban {
+            id: self.next_id,
+            name: ban.name,
+            ip: ban.ip,
+            reason: ban.reason,
+            issued_by: ban.issued_by.unwrap_or_else(|| "api".to_string()),
+            issued_at: now,
+            expires_at: ban.duration.map(|duration| now.saturating_add(duration)),
+        };
+
+        self.bans.push(ban.clone());
+        self.save();
+        ban
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Ban> {
+        let index = self.bans.iter().position(|ban| ban.id == id)?;
+        let ban = self.bans.remove(index);
+        self.save();
+        Some(ban)
+    }
+
+    pub fn list(&mut self) -> Vec<Ban> {
+        self.prune();
+        self.bans.clone()
+    }
+
+    /// The active ban for a player with this name or ip, if any
+    pub fn find(&mut self, name: &str, ip: &str) -> Option<Ban> {
+        self.prune();
+        self.bans.iter().find(|ban| ban.matches(name, ip)).cloned()
+    }
+}
+
+impl ServerInfoHandle {
+    pub fn load_bans(&self) {
+        *self.bans.lock().unwrap() = BanList::load();
+    }
+
+    /// Kicks the player if they're banned, returns true if they were
+    pub fn enforce_ban(&self, player_id: c_ushort) -> bool {
+        let name = get_name(player_id);
+        let ip = get_ip(player_id);
+        let ban = match self.bans.lock().unwrap().find(&name, &ip) {
+            Some(ban) => ban,
+            None => return false,
+        };
+
+        log_message(
+            LOG_INFO,
+            format!(
+                "Kicking banned player {} ({}), ban {}: {}",
+                name, ip, ban.id, ban.reason
+            )
+            .as_str(),
+        );
+        kick(player_id);
+        true
+    }
+
+    pub fn get_bans(&self) -> Vec<Ban> {
+        self.bans.lock().unwrap().list()
+    }
+
+    /// Adds a ban and kicks every online player it applies to
+    pub async fn add_ban(&self, mut ban: NewBan) -> Result<Ban, CommandError> {
+        ban.validate(timestamp())?;
+
+        let ban = self.bans.lock().unwrap().add(ban);
+        log_message(
+            LOG_INFO,
+            format!(
+                "{} banned {} until {}: {}",
+                ban.issued_by,
+                ban.name.as_ref().or(ban.ip.as_ref()).unwrap(),
+                ban.expires_at
+                    .map_or("forever".to_string(), |time| time.to_string()),
+                ban.reason
+            )
+            .as_str(),
+        );
+
+        let banned: Vec<c_ushort> = self
+            .info
+            .read()
+            .await
+            .players
+            .values()
+            .filter(|player| ban.matches(&player.name, &player.ip))
+            .map(|player| player.id)
+            .collect();
+
+        for player_id in banned {
+            // The player may have left in the meantime
+            let _ = self.execute(AdminCommand::Kick { player_id }).await;
+        }
+
+        Ok(ban)
+    }
+
+    pub fn remove_ban(&self, id: u64) -> Option<Ban> {
+        self.bans.lock().unwrap().remove(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban(name: Option<&str>, ip: Option<&str>, expires_at: Option<u64>) -> Ban {
+        Ban {
+            id: 1,
+            name: name.map(str::to_string),
+            ip: ip.map(str::to_string),
+            reason: String::new(),
+            issued_by: "api".to_string(),
+            issued_at: 0,
+            expires_at,
+        }
+    }
+
+    fn new_ban(name: &str, ip: &str, duration: Option<u64>) -> NewBan {
+        NewBan {
+            name: Some(name.to_string()),
+            ip: Some(ip.to_string()),
+            reason: String::new(),
+            duration,
+            issued_by: None,
+        }
+    }
+
+    #[test]
+    fn matches_names_ignoring_case() {
+        let ban = ban(Some("Fargoth"), None, None);
+
+        assert!(ban.matches("fargoth", "127.0.0.1"));
+        assert!(ban.matches("FARGOTH", ""));
+        assert!(!ban.matches("Vivec", "127.0.0.1"));
+    }
+
+    #[test]
+    fn matches_ips() {
+        let ban = ban(None, Some("10.0.0.1"), None);
+
+        assert!(ban.matches("Fargoth", "10.0.0.1"));
+        assert!(!ban.matches("Fargoth", "10.0.0.2"));
+        assert!(!ban.matches("", ""));
+    }
+
+    #[test]
+    fn matches_either_name_or_ip() {
+        let ban = ban(Some("Fargoth"), Some("10.0.0.1"), None);
+
+        assert!(ban.matches("Fargoth", "10.0.0.2"));
+        assert!(ban.matches("Vivec", "10.0.0.1"));
+        assert!(!ban.matches("Vivec", "10.0.0.2"));
+    }
+
+    #[test]
+    fn expires() {
+        assert!(!ban(Some("Fargoth"), None, None).is_expired(u64::MAX));
+        assert!(!ban(Some("Fargoth"), None, Some(100)).is_expired(99));
+        assert!(ban(Some("Fargoth"), None, Some(100)).is_expired(100));
+    }
+
+    #[test]
+    fn finds_active_bans_only() {
+        let mut list = BanList {
+            next_id: 2,
+            bans: vec![
+                ban(Some("Fargoth"), None, None),
+                ban(None, Some("10.0.0.1"), Some(u64::MAX)),
+            ],
+        };
+
+        assert!(list.find("fargoth", "10.0.0.2").is_some());
+        assert!(list.find("Vivec", "10.0.0.1").is_some());
+        assert!(list.find("Vivec", "10.0.0.2").is_none());
+    }
+
+    #[test]
+    fn removes_expired_bans() {
+        let mut list = BanList {
+            next_id: 3,
+            bans: vec![
+                ban(Some("Fargoth"), None, None),
+                ban(Some("Vivec"), None, Some(100)),
+                ban(None, Some("10.0.0.1"), Some(200)),
+            ],
+        };
+
+        assert!(!list.remove_expired(99));
+        assert_eq!(list.bans.len(), 3);
+
+        assert!(list.remove_expired(100));
+        assert_eq!(list.bans.len(), 2);
+        assert!(!list.bans.iter().any(|ban| ban.matches("Vivec", "")));
+        assert!(list.bans.iter().any(|ban| ban.matches("Fargoth", "")));
+
+        assert!(list.remove_expired(u64::MAX));
+        assert_eq!(list.bans.len(), 1);
+    }
+
+    #[test]
+    fn validates_new_bans() {
+        let mut ban = new_ban("", "10.0.0.1", Some(60));
+        assert_eq!(ban.validate(1000), Ok(()));
+        assert_eq!(ban.name, None);
+        assert_eq!(ban.ip.as_deref(), Some("10.0.0.1"));
+
+        assert_eq!(
+            new_ban("", "", None).validate(1000),
+            Err(CommandError::Invalid("Expected a name or an ip"))
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_durations() {
+        assert_eq!(
+            new_ban("Fargoth", "", Some(u64::MAX)).validate(1000),
+            Err(CommandError::Invalid("Duration is out of range"))
+        );
+        assert_eq!(
+            new_ban("Fargoth", "", Some(u64::MAX - 1000)).validate(1000),
+            Ok(())
+        );
+    }
+}
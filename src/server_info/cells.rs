@@ -0,0 +1,79 @@
+use crate::server_info::counters::CELL_PLAYERS;
+use crate::server_info::player_details::Player;
+use crate::statsd;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::os::raw::c_ushort;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CellPlayer {
+    pub id: c_ushort,
+    pub name: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CellOccupancy {
+    pub cell: String,
+    pub players: Vec<CellPlayer>,
+}
+
+/// Tracks which logged in players are in which cell
+#[derive(Default, Debug)]
+pub struct CellIndex {
+    cells: BTreeMap<String, BTreeSet<c_ushort>>,
+}
+
+impl CellIndex {
+    pub fn enter(&mut self, cell: &str, player_id: c_ushort) {
+        let players = self.cells.entry(cell.to_string()).or_default();
+        players.insert(player_id);
+        CELL_PLAYERS
+            .with_label_values(&[cell])
+            .set(players.len() as i64);
+        statsd::gauge("cell.players", players.len() as f64, &[("cell", cell)]);
+    }
+
+    pub fn leave(&mut self, cell: &str, player_id: c_ushort) {
+        let players = match self.cells.get_mut(cell) {
+            Some(players) => players,
+            None => return,
+        };
+
+        players.remove(&player_id);
+        if players.is_empty() {
+            self.cells.remove(cell);
+            let _ = CELL_PLAYERS.remove_label_values(&[cell]);
+            // StatsD keeps the last value of a gauge around, so it has to go to 0
+            statsd::gauge("cell.players", 0.0, &[("cell", cell)]);
+        } else {
+            CELL_PLAYERS
+                .with_label_values(&[cell])
+                .set(players.len() as i64);
+            statsd::gauge("cell.players", players.len() as f64, &[("cell", cell)]);
+        }
+    }
+
+    pub fn move_player(&mut self, from: &str, to: &str, player_id: c_ushort) {
+        self.leave(from, player_id);
+        self.enter(to, player_id);
+    }
+
+    pub fn get_cells(&self, players: &HashMap<c_ushort, Player>) -> Vec<CellOccupancy> {
+        self.cells
+            .iter()
+            .map(|(cell, ids)| CellOccupancy {
+                cell: cell.clone(),
+                players: ids
+                    .iter()
+                    .filter_map(|id| players.get(id))
+                    .map(|player| CellPlayer {
+                        id: player.id,
+                        name: player.name.clone(),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+}
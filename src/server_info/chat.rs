@@ -0,0 +1,83 @@
+use crate::config::CONFIG;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::os::raw::c_ushort;
+
+lazy_static! {
+    static ref REDACTIONS: Vec<Regex> = CONFIG
+        .chat
+        .redact
+        .iter()
+        .filter_map(|pattern| Regex::new(pattern).ok())
+        .collect();
+}
+
+const REDACTED: &str = "[redacted]";
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatMessage {
+    pub id: u64,
+    pub player_id: c_ushort,
+    pub name: String,
+    pub cell: String,
+    pub message: String,
+    /// Sent with one of the configured private message commands
+    pub private: bool,
+    pub timestamp: u64,
+}
+
+impl ChatMessage {
+    pub fn is_private(message: &str) -> bool {
+        CONFIG.chat.private_commands.iter().any(|command| {
+            message
+                .strip_prefix(command.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+        })
+    }
+
+    /// Replaces every match of the configured redaction patterns in private messages
+    pub fn redact(&mut self) {
+        if !self.private {
+            return;
+        }
+
+        for pattern in REDACTIONS.iter() {
+            self.message = pattern.replace_all(&self.message, REDACTED).into_owned();
+        }
+    }
+}
+
+/// The last `chat.history_size` chat messages, ids keep increasing so clients can poll with `since`
+#[derive(Default, Debug)]
+pub struct ChatLog {
+    next_id: u64,
+    messages: VecDeque<ChatMessage>,
+}
+
+impl ChatLog {
+    /// Assigns the message its id and stores it, dropping the oldest message when full
+    pub fn push(&mut self, mut message: ChatMessage) -> ChatMessage {
+        self.next_id += 1;
+        message.id = self.next_id;
+        message.redact();
+
+        self.messages.push_back(message.clone());
+        while self.messages.len() > CONFIG.chat.history_size {
+            self.messages.pop_front();
+        }
+
+        message
+    }
+
+    /// Every stored message with an id higher than `since`, private ones only if `private` is set
+    pub fn since(&self, since: u64, private: bool) -> Vec<ChatMessage> {
+        self.messages
+            .iter()
+            .filter(|message| message.id > since && (private || !message.private))
+            .cloned()
+            .collect()
+    }
+}
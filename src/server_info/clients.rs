@@ -0,0 +1,432 @@
+use crate::config::CONFIG;
+use crate::plugin::{log_message, LOG_INFO};
+use crate::server::SERVER_VERSION;
+use crate::server_info::counters::{DROPPED_FRAMES, EVENTS_PUBLISHED, STREAM_CLIENTS};
+use crate::server_info::events::{HelloEvent, WebsocketEvent};
+use crate::server_info::logic::{ServerInfo, ServerInfoHandle, ServerLogic};
+use crate::server_info::outbox::{Frame, Outbox, OverflowPolicy};
+use crate::server_info::subscription::{ClientMessage, Subscription};
+use crate::statsd;
+use futures_util::stream::{self, BoxStream};
+use futures_util::{SinkExt, StreamExt};
+use std::borrow::Cow;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::time::{interval, timeout};
+use warp::ws::{Message, WebSocket};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClientKind {
+    WebSocket,
+    Sse,
+}
+
+impl ClientKind {
+    fn label(self) -> &'static str {
+        match self {
+            ClientKind::WebSocket => "websocket",
+            ClientKind::Sse => "sse",
+        }
+    }
+}
+
+impl fmt::Display for ClientKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientKind::WebSocket => write!(f, "Websocket"),
+            ClientKind::Sse => write!(f, "SSE client"),
+        }
+    }
+}
+
+fn count_dropped_frame(policy: OverflowPolicy) {
+    DROPPED_FRAMES.with_label_values(&[policy.label()]).inc();
+    statsd::count(
+        "websocket.dropped_frames",
+        1.0,
+        &[("policy", policy.label())],
+    );
+}
+
+/// A websocket or SSE connection receiving events
+#[derive(Debug)]
+pub struct Client {
+    pub kind: ClientKind,
+    pub outbox: Arc<Outbox>,
+    pub subscription: Subscription,
+}
+
+impl Client {
+    /// Queues the event if the client is subscribed to it, returns false if the client should be dropped
+    fn queue(&self, event_id: u64, event: &WebsocketEvent, json: &str) -> bool {
+        let json = match self.subscription.filter(event) {
+            None => return !self.outbox.is_closed(),
+            Some(Cow::Borrowed(_)) => json.to_string(),
+            Some(Cow::Owned(filtered)) => serde_json::to_string(&filtered).unwrap(),
+        };
+
+        self.outbox.push(Frame::new(event_id, event, json))
+    }
+}
+
+impl ServerLogic {
+    fn update_client_gauge(&self) {
+        for kind in &[ClientKind::WebSocket, ClientKind::Sse] {
+            let count = self.clients.values().filter(|c| c.kind == *kind).count();
+            STREAM_CLIENTS
+                .with_label_values(&[kind.label()])
+                .set(count as i64);
+            statsd::gauge("stream.clients", count as f64, &[("kind", kind.label())]);
+        }
+    }
+
+    /// Keeps replayable events, dropping the oldest once the history is full
+    fn record(&mut self, event_id: u64, event: WebsocketEvent) {
+        if !event.is_replayable() {
+            return;
+        }
+
+        self.history.push_back((event_id, event));
+        while self.history.len() > self.history_size {
+            if let Some((evicted, _)) = self.history.pop_front() {
+                self.evicted_event_id = evicted;
+            }
+        }
+    }
+
+    /// Checks if every replayable event after `last_event_id` is still in the history and fits in a client's queue
+    fn can_resume(&self, last_event_id: u64) -> bool {
+        if last_event_id < self.evicted_event_id || last_event_id > self.event_id {
+            return false;
+        }
+
+        let missed = self
+            .history
+            .iter()
+            .filter(|(id, _)| *id > last_event_id)
+            .count();
+        missed <= self.queue_size
+    }
+}
+
+/// Closes the outbox once the SSE response stream is dropped
+struct SseGuard {
+    id: u64,
+    remote: String,
+    outbox: Arc<Outbox>,
+}
+
+impl Drop for SseGuard {
+    fn drop(&mut self) {
+        self.outbox.close();
+        log_message(
+            LOG_INFO,
+            format!("SSE client {} from {} disconnected", self.id, self.remote).as_str(),
+        );
+    }
+}
+
+fn remote_name(remote: Option<SocketAddr>) -> String {
+    remote.map_or_else(|| "unknown".to_string(), |addr| addr.to_string())
+}
+
+impl ServerInfoHandle {
+    /// Queues the event for every client before returning, so clients see events in the order they're published
+    pub async fn publish_event(&self, event: WebsocketEvent) {
+        let mut logic = self.logic.lock().await;
+        EVENTS_PUBLISHED.with_label_values(&[event.name()]).inc();
+        statsd::count("events.published", 1.0, &[("event", event.name())]);
+        logic.event_id += 1;
+        let event_id = logic.event_id;
+        let json = serde_json::to_string(&event).unwrap();
+
+        let mut to_remove = vec![];
+        for (id, client) in &logic.clients {
+            if !client.queue(event_id, &event, &json) {
+                to_remove.push(*id);
+            }
+        }
+
+        if !to_remove.is_empty() {
+            for id in to_remove {
+                logic.clients.remove(&id);
+            }
+
+            logic.update_client_gauge();
+        }
+
+        logic.record(event_id, event);
+    }
+
+    fn hello(info: &ServerInfo) -> WebsocketEvent {
+        WebsocketEvent::Hello(HelloEvent {
+            server_version: SERVER_VERSION.clone(),
+            plugin_version: env!("CARGO_PKG_VERSION").to_string(),
+            tick_rate: 1000.0 / CONFIG.tick_interval as f64,
+            low_frequency_divisor: CONFIG.low_frequency_divisor,
+            players: info.delta.snapshot(),
+        })
+    }
+
+    /// Registers a client and queues either a hello or the events it missed since `last_event_id`
+    async fn register_client(
+        &self,
+        kind: ClientKind,
+        subscription: Subscription,
+        last_event_id: Option<u64>,
+        remote: &str,
+    ) -> (u64, Arc<Outbox>) {
+        let outbox = Arc::new(Outbox::new(
+            CONFIG.websocket.queue_size,
+            CONFIG.websocket.overflow,
+            count_dropped_frame,
+        ));
+
+        // Queueing while holding both locks makes sure no event is queued before these,
+        // and that no delta is published between the snapshot and registering the client
+        let info = self.info.read().await;
+        let mut logic = self.logic.lock().await;
+        match last_event_id.filter(|last| logic.can_resume(*last)) {
+            Some(last_event_id) => {
+                for (event_id, event) in logic.history.iter().filter(|(id, _)| *id > last_event_id)
+                {
+                    if let Some(event) = subscription.filter(event) {
+                        let json = serde_json::to_string(&event).unwrap();
+                        outbox.push(Frame::new(*event_id, &event, json));
+                    }
+                }
+            }
+            None => {
+                let hello = ServerInfoHandle::hello(&info);
+                if let Some(hello) = subscription.filter_players(&hello) {
+                    let json = serde_json::to_string(&hello).unwrap();
+                    outbox.push(Frame::new(logic.event_id, &hello, json));
+                }
+            }
+        }
+
+        let id = logic.next_id;
+        logic.next_id += 1;
+        logic.clients.insert(
+            id,
+            Client {
+                kind,
+                outbox: outbox.clone(),
+                subscription,
+            },
+        );
+        logic.update_client_gauge();
+
+        log_message(
+            LOG_INFO,
+            format!("{} {} connected from {}", kind, id, remote).as_str(),
+        );
+
+        (id, outbox)
+    }
+
+    /// Registers the websocket and handles its incoming messages until it's closed
+    pub async fn add_websocket(
+        &self,
+        ws: WebSocket,
+        subscription: Subscription,
+        remote: Option<SocketAddr>,
+    ) {
+        let remote = remote_name(remote);
+        let (mut sink, mut stream) = ws.split();
+        let (id, outbox) = self
+            .register_client(ClientKind::WebSocket, subscription, None, &remote)
+            .await;
+
+        let writer_outbox = outbox.clone();
+        let mut writer = self.handle.spawn(async move {
+            let mut ping = interval(Duration::from_secs(CONFIG.websocket.ping_interval));
+
+            loop {
+                let messages: Vec<Message> = select! {
+                    frames = writer_outbox.pop() => match frames {
+                        Some(frames) => frames
+                            .into_iter()
+                            .map(|frame| Message::text(frame.json))
+                            .collect(),
+                        None => break,
+                    },
+                    _ = ping.tick() => vec![Message::ping(Vec::new())],
+                };
+
+                for message in messages {
+                    if sink.send(message).await.is_err() {
+                        writer_outbox.close();
+                        return;
+                    }
+                }
+            }
+
+            let _ = sink.close().await;
+        });
+
+        let idle_timeout = Duration::from_secs(CONFIG.websocket.idle_timeout);
+        let reason = loop {
+            // Any frame, including pongs, counts as activity
+            let message = select! {
+                message = timeout(idle_timeout, stream.next()) => match message {
+                    Err(_) => break "idle timeout",
+                    Ok(None) => break "connection closed",
+                    Ok(Some(Err(_))) => break "connection error",
+                    Ok(Some(Ok(message))) => message,
+                },
+                // The writer closed the websocket, either after an error or because the outbox was closed
+                _ = &mut writer => break "outbound queue closed",
+            };
+
+            if message.is_close() {
+                break "closed by client";
+            }
+
+            let client_message = match message
+                .to_str()
+                .ok()
+                .and_then(|text| serde_json::from_str::<ClientMessage>(text).ok())
+            {
+                Some(client_message) => client_message,
+                None => continue,
+            };
+
+            let info = self.info.read().await;
+            let mut logic = self.logic.lock().await;
+            let event_id = logic.event_id;
+            if let Some(client) = logic.clients.get_mut(&id) {
+                client.subscription.apply(&client_message);
+
+                if client_message.wants_snapshot() {
+                    let snapshot = WebsocketEvent::FullPlayer(info.delta.snapshot());
+                    if let Some(event) = client.subscription.filter_players(&snapshot) {
+                        let json = serde_json::to_string(&event).unwrap();
+                        client.outbox.push(Frame::new(event_id, &event, json));
+                    }
+                }
+            }
+        };
+
+        outbox.close();
+        let mut logic = self.logic.lock().await;
+        if logic.clients.remove(&id).is_some() {
+            logic.update_client_gauge();
+        }
+        drop(logic);
+
+        log_message(
+            LOG_INFO,
+            format!("Websocket {} from {} disconnected ({})", id, remote, reason).as_str(),
+        );
+    }
+
+    /// Registers an SSE client, the returned stream ends once the client is dropped
+    pub async fn add_sse_client(
+        &self,
+        subscription: Subscription,
+        last_event_id: Option<u64>,
+        remote: Option<SocketAddr>,
+    ) -> BoxStream<'static, Frame> {
+        let remote = remote_name(remote);
+        let (id, outbox) = self
+            .register_client(ClientKind::Sse, subscription, last_event_id, &remote)
+            .await;
+
+        let guard = SseGuard { id, remote, outbox };
+        stream::unfold(guard, |guard| async move {
+            let frames = guard.outbox.pop().await?;
+            Some((stream::iter(frames), guard))
+        })
+        .flatten()
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_info::chat::ChatMessage;
+    use crate::server_info::events::PlayerPositionEvent;
+
+    fn publish(logic: &mut ServerLogic, event: WebsocketEvent) {
+        logic.event_id += 1;
+        let event_id = logic.event_id;
+        logic.record(event_id, event);
+    }
+
+    fn chat() -> WebsocketEvent {
+        WebsocketEvent::ChatMessage(ChatMessage {
+            id: 0,
+            player_id: 0,
+            name: "Fargoth".to_string(),
+            cell: "Seyda Neen".to_string(),
+            message: "Hello".to_string(),
+            private: false,
+            timestamp: 0,
+        })
+    }
+
+    fn positions() -> WebsocketEvent {
+        WebsocketEvent::PlayerPosition(PlayerPositionEvent { positions: vec![] })
+    }
+
+    #[test]
+    fn only_keeps_replayable_events() {
+        let mut logic = ServerLogic::new(4, 2);
+        publish(&mut logic, chat());
+        publish(&mut logic, positions());
+        publish(&mut logic, chat());
+
+        let ids: Vec<u64> = logic.history.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![1, 3]);
+    }
+
+    #[test]
+    fn resumes_across_position_events() {
+        let mut logic = ServerLogic::new(4, 2);
+        publish(&mut logic, chat());
+        for _ in 0..8 {
+            publish(&mut logic, positions());
+        }
+
+        assert!(logic.can_resume(0));
+        assert!(logic.can_resume(1));
+        assert!(logic.can_resume(logic.event_id));
+    }
+
+    #[test]
+    fn cant_resume_after_eviction() {
+        let mut logic = ServerLogic::new(4, 4);
+        for _ in 0..6 {
+            publish(&mut logic, chat());
+        }
+
+        assert_eq!(logic.history.len(), 4);
+        assert_eq!(logic.evicted_event_id, 2);
+        assert!(!logic.can_resume(1));
+        assert!(logic.can_resume(2));
+    }
+
+    #[test]
+    fn cant_resume_future_events() {
+        let mut logic = ServerLogic::new(4, 2);
+        publish(&mut logic, chat());
+
+        assert!(!logic.can_resume(2));
+    }
+
+    #[test]
+    fn cant_resume_more_than_fits_in_the_queue() {
+        let mut logic = ServerLogic::new(4, 2);
+        for _ in 0..3 {
+            publish(&mut logic, chat());
+        }
+
+        assert!(!logic.can_resume(0));
+        assert!(logic.can_resume(1));
+    }
+}
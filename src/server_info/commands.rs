@@ -0,0 +1,228 @@
+use crate::plugin::*;
+use crate::server_info::logic::{ServerInfo, ServerInfoHandle};
+use crate::server_info::player_details::Vec3;
+use serde::Deserialize;
+use std::fmt;
+use std::os::raw::{c_double, c_ushort};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+/// How long an HTTP request waits for the game thread to run its command
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A chat message sent from the HTTP API
+#[derive(Deserialize, Clone, Debug)]
+pub struct OutgoingMessage {
+    pub message: String,
+    /// Only send to this player instead of everyone
+    pub target: Option<c_ushort>,
+    /// Color prefix in tes3mp's `#rrggbb` format, the `#` is optional
+    pub color: Option<String>,
+    /// Shown in brackets in front of the message, e.g. `[Discord]`
+    pub sender: Option<String>,
+}
+
+impl OutgoingMessage {
+    pub fn format(&self) -> String {
+        let mut message = String::new();
+        if let Some(color) = self.color.as_deref().and_then(parse_color) {
+            message.push_str(&color);
+        }
+
+        if let Some(sender) = &self.sender {
+            message.push_str(&format!("[{}] ", sender));
+        }
+
+        message.push_str(self.message.trim_end());
+        message.push('\n');
+        message
+    }
+}
+
+/// Checks for a `#rrggbb` color, the `#` is optional
+pub fn parse_color(color: &str) -> Option<String> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(format!("#{}", hex))
+    } else {
+        None
+    }
+}
+
+/// Write operations from the HTTP thread, executed on the server thread in `tick()`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "camelCase")]
+pub enum AdminCommand {
+    #[serde(rename_all = "camelCase")]
+    Kick {
+        player_id: c_ushort,
+    },
+    Message(OutgoingMessage),
+    #[serde(rename_all = "camelCase")]
+    Teleport {
+        player_id: c_ushort,
+        cell: Option<String>,
+        position: Option<Vec3>,
+    },
+    #[serde(rename_all = "camelCase")]
+    SetHealth {
+        player_id: c_ushort,
+        health: c_double,
+    },
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CommandError {
+    PlayerNotFound,
+    NobodyOnline,
+    Invalid(&'static str),
+    /// The server thread didn't run the command in time
+    Timeout,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::PlayerNotFound => write!(f, "Player not found"),
+            CommandError::NobodyOnline => write!(f, "Nobody is online"),
+            CommandError::Invalid(reason) => write!(f, "{}", reason),
+            CommandError::Timeout => write!(f, "Timed out waiting for the server"),
+        }
+    }
+}
+
+pub type CommandResult = Result<(), CommandError>;
+
+#[derive(Debug)]
+pub struct QueuedCommand {
+    command: AdminCommand,
+    result: oneshot::Sender<CommandResult>,
+}
+
+impl AdminCommand {
+    /// Checks what can be checked without calling tes3mp
+    fn validate(&self) -> CommandResult {
+        match self {
+            AdminCommand::Message(message) => {
+                if message.message.trim().is_empty() {
+                    return Err(CommandError::Invalid("Message can't be empty"));
+                }
+
+                if message
+                    .color
+                    .as_deref()
+                    .is_some_and(|c| parse_color(c).is_none())
+                {
+                    return Err(CommandError::Invalid("Invalid color, expected #rrggbb"));
+                }
+            }
+            AdminCommand::Teleport { cell, position, .. } => {
+                if cell.is_none() && position.is_none() {
+                    return Err(CommandError::Invalid("Expected a cell or a position"));
+                }
+            }
+            AdminCommand::SetHealth { health, .. } => {
+                if !health.is_finite() || *health < 0.0 {
+                    return Err(CommandError::Invalid("Health has to be a positive number"));
+                }
+            }
+            AdminCommand::Kick { .. } => {}
+        }
+
+        Ok(())
+    }
+
+    fn run(&self, info: &ServerInfo) -> CommandResult {
+        let in_game = |player_id: &c_ushort| match info.players.get(player_id) {
+            Some(player) if player.logged_in => Ok(*player_id),
+            _ => Err(CommandError::PlayerNotFound),
+        };
+
+        match self {
+            // Only checked here, tick() kicks once the server and info locks are released
+            AdminCommand::Kick { player_id } => {
+                if !info.players.contains_key(player_id) {
+                    return Err(CommandError::PlayerNotFound);
+                }
+            }
+            AdminCommand::Message(message) => {
+                let text = message.format();
+                match message.target {
+                    Some(target) => send_message(in_game(&target)?, &text, false, false),
+                    None => match info.players.values().find(|p| p.logged_in) {
+                        Some(player) => send_message(player.id, &text, true, false),
+                        None => return Err(CommandError::NobodyOnline),
+                    },
+                }
+            }
+            AdminCommand::Teleport {
+                player_id,
+                cell,
+                position,
+            } => {
+                let player_id = in_game(player_id)?;
+                if let Some(cell) = cell {
+                    set_cell(player_id, cell);
+                    send_cell(player_id);
+                }
+
+                if let Some(position) = position {
+                    set_pos(player_id, position.x, position.y, position.z);
+                    send_pos(player_id);
+                }
+            }
+            AdminCommand::SetHealth { player_id, health } => {
+                let player_id = in_game(player_id)?;
+                set_health_current(player_id, *health);
+                send_stats_dynamic(player_id);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ServerInfoHandle {
+    /// Queues a command for the server thread and waits for its result,
+    /// tes3mp functions may only be called from the server thread
+    pub async fn execute(&self, command: AdminCommand) -> CommandResult {
+        command.validate()?;
+
+        let (sender, receiver) = oneshot::channel();
+        self.commands.lock().unwrap().push_back(QueuedCommand {
+            command,
+            result: sender,
+        });
+
+        match timeout(COMMAND_TIMEOUT, receiver).await {
+            Ok(Ok(result)) => result,
+            _ => Err(CommandError::Timeout),
+        }
+    }
+
+    /// Runs every queued command, called from `tick()`. Returns the players to kick,
+    /// kicking can call back into the plugin so `tick()` does it once it released its locks
+    pub async fn run_commands(&self) -> Vec<c_ushort> {
+        let commands: Vec<QueuedCommand> = self.commands.lock().unwrap().drain(..).collect();
+        let mut kicks = vec![];
+
+        for queued in commands {
+            // The request timed out and already got an error, running the command now would surprise whoever sent it
+            if queued.result.is_closed() {
+                continue;
+            }
+
+            let result = queued.command.run(&*self.info.read().await);
+            if let (Ok(()), AdminCommand::Kick { player_id }) = (&result, &queued.command) {
+                kicks.push(*player_id);
+            }
+
+            // The request can still time out while the command runs
+            let _ = queued.result.send(result);
+        }
+
+        kicks
+    }
+}
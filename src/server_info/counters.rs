@@ -65,10 +65,97 @@ lazy_static! {
         &["player"]
     )
     .unwrap();
-    pub static ref DISTANCE_TRAVELED: HistogramVec = register_histogram_vec!(
-        "openmw_player_distance_traveled",
-        "The amount of distance a player has travelled",
+    pub static ref DEATHS: IntCounterVec = register_int_counter_vec!(
+        "openmw_player_deaths_total",
+        "The amount of times players died",
         &["player"]
     )
     .unwrap();
+    pub static ref KILLS: IntCounterVec = register_int_counter_vec!(
+        "openmw_player_kills_total",
+        "The amount of players killed by players",
+        &["player"]
+    )
+    .unwrap();
+    pub static ref DISTANCE: CounterVec = register_counter_vec!(
+        "openmw_player_distance_total",
+        "The distance players travelled, without teleports",
+        &["player"]
+    )
+    .unwrap();
+    pub static ref CELL_PLAYERS: IntGaugeVec = register_int_gauge_vec!(
+        "openmw_cell_players",
+        "The amount of players in a cell",
+        &["cell"]
+    )
+    .unwrap();
+    pub static ref DROPPED_FRAMES: IntCounterVec = register_int_counter_vec!(
+        "openmw_websocket_dropped_frames_total",
+        "The amount of websocket frames dropped because a client couldn't keep up",
+        &["policy"]
+    )
+    .unwrap();
+    pub static ref AUTH_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "openmw_http_auth_failures_total",
+        "The amount of HTTP requests rejected for missing, invalid or insufficient credentials",
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref PLAYERS_ONLINE: IntGauge = register_int_gauge!(
+        "openmw_players_online",
+        "The amount of connected players, including those still logging in"
+    )
+    .unwrap();
+    pub static ref PLAYERS_LOGGED_IN: IntGauge = register_int_gauge!(
+        "openmw_players_logged_in",
+        "The amount of players that are in game"
+    )
+    .unwrap();
+    pub static ref STREAM_CLIENTS: IntGaugeVec = register_int_gauge_vec!(
+        "openmw_stream_clients",
+        "The amount of connected websocket and SSE clients",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref EVENTS_PUBLISHED: IntCounterVec = register_int_counter_vec!(
+        "openmw_events_published_total",
+        "The amount of events published to websocket and SSE clients",
+        &["event"]
+    )
+    .unwrap();
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "openmw_http_requests_total",
+        "The amount of HTTP requests handled",
+        &["route", "method", "status"]
+    )
+    .unwrap();
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "openmw_http_request_duration_seconds",
+        "The time it took to answer HTTP requests",
+        &["route"]
+    )
+    .unwrap();
+    pub static ref TICK_DURATION: Histogram = register_histogram!(
+        "openmw_tick_duration_seconds",
+        "The time spent in a tick",
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]
+    )
+    .unwrap();
+    pub static ref UPDATE_PLAYERS_DURATION: Histogram = register_histogram!(
+        "openmw_update_players_duration_seconds",
+        "The time spent updating players in a tick",
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]
+    )
+    .unwrap();
+    pub static ref TICK_DRIFT: Histogram = register_histogram!(
+        "openmw_tick_drift_seconds",
+        "How much later than the configured tick interval a tick started",
+        vec![-0.01, 0.0, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]
+    )
+    .unwrap();
+    pub static ref PUSH_FAILURES: IntCounter = register_int_counter!(
+        "openmw_metrics_push_failures_total",
+        "The amount of failed attempts to push metrics, including retries"
+    )
+    .unwrap();
 }
@@ -0,0 +1,214 @@
+use crate::server_info::events::{FullPlayerEvent, PlayerDelta, PlayerDeltaEvent};
+use crate::server_info::player_details::Player;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::os::raw::c_ushort;
+
+/// Keeps the last broadcasted player state so the next broadcast only has to carry the changes
+#[derive(Default, Debug)]
+pub struct DeltaState {
+    pub seq: u64,
+    snapshot: Vec<Player>,
+    values: HashMap<c_ushort, Map<String, Value>>,
+}
+
+impl DeltaState {
+    /// Diffs `players` against the previous broadcast, returns `None` if nothing changed
+    pub fn update(&mut self, players: &[Player]) -> Option<PlayerDeltaEvent> {
+        let mut values = HashMap::new();
+        let mut changed = vec![];
+
+        for player in players {
+            let current = match serde_json::to_value(player) {
+                Ok(Value::Object(map)) => map,
+                _ => continue,
+            };
+
+            let mut fields = match self.values.get(&player.id) {
+                Some(previous) => diff(previous, &current),
+                None => current.clone(),
+            };
+            fields.remove("id");
+
+            if !fields.is_empty() {
+                changed.push(PlayerDelta {
+                    id: player.id,
+                    name: player.name.clone(),
+                    cell: player.cell.clone(),
+                    fields,
+                });
+            }
+
+            values.insert(player.id, current);
+        }
+
+        let removed: Vec<c_ushort> = self
+            .values
+            .keys()
+            .filter(|id| !values.contains_key(id))
+            .copied()
+            .collect();
+
+        self.values = values;
+        self.snapshot = players.to_vec();
+
+        if changed.is_empty() && removed.is_empty() {
+            return None;
+        }
+
+        self.seq += 1;
+        Some(PlayerDeltaEvent {
+            seq: self.seq,
+            players: changed,
+            removed,
+        })
+    }
+
+    /// The state as of the last broadcast, deltas with a higher `seq` apply on top of it
+    pub fn snapshot(&self) -> FullPlayerEvent {
+        FullPlayerEvent {
+            seq: self.seq,
+            players: self.snapshot.clone(),
+        }
+    }
+}
+
+/// Returns the fields of `current` that differ from `previous`,
+/// arrays of objects with an `id` (skills, attributes) only contain the changed entries
+fn diff(previous: &Map<String, Value>, current: &Map<String, Value>) -> Map<String, Value> {
+    let mut fields = Map::new();
+
+    for (key, value) in current {
+        let old = match previous.get(key) {
+            Some(old) if old == value => continue,
+            old => old,
+        };
+
+        let value = match (old, value) {
+            (Some(Value::Array(old)), Value::Array(new)) if is_keyed(old) && is_keyed(new) => {
+                Value::Array(
+                    new.iter()
+                        .filter(|entry| !old.contains(entry))
+                        .cloned()
+                        .collect(),
+                )
+            }
+            _ => value.clone(),
+        };
+
+        fields.insert(key.clone(), value);
+    }
+
+    fields
+}
+
+fn is_keyed(values: &[Value]) -> bool {
+    values.iter().all(|value| value.get("id").is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_info::player_details::SkillValue;
+    use std::os::raw::c_int;
+
+    fn player(id: c_ushort, name: &str, level: c_int) -> Player {
+        Player {
+            id,
+            name: name.to_string(),
+            cell: "Balmora".to_string(),
+            level,
+            skills: vec![skill(0, 5), skill(1, 10)],
+            ..Player::default()
+        }
+    }
+
+    fn skill(id: c_ushort, base: c_int) -> SkillValue {
+        SkillValue {
+            id,
+            base,
+            ..SkillValue::default()
+        }
+    }
+
+    #[test]
+    fn added_players_get_every_field() {
+        let mut state = DeltaState::default();
+        let delta = state.update(&[player(1, "Fargoth", 1)]).unwrap();
+
+        assert_eq!(delta.seq, 1);
+        assert!(delta.removed.is_empty());
+        assert_eq!(delta.players.len(), 1);
+        assert_eq!(delta.players[0].id, 1);
+        assert_eq!(delta.players[0].name, "Fargoth");
+        assert_eq!(delta.players[0].fields["level"], 1);
+        assert_eq!(
+            delta.players[0].fields["skills"].as_array().unwrap().len(),
+            2
+        );
+        assert!(!delta.players[0].fields.contains_key("id"));
+    }
+
+    #[test]
+    fn changed_players_only_get_changed_fields() {
+        let mut state = DeltaState::default();
+        state.update(&[player(1, "Fargoth", 1), player(2, "Vivec", 50)]);
+
+        let mut fargoth = player(1, "Fargoth", 2);
+        fargoth.skills[1].base = 11;
+        let delta = state.update(&[fargoth, player(2, "Vivec", 50)]).unwrap();
+
+        assert_eq!(delta.seq, 2);
+        assert_eq!(delta.players.len(), 1);
+        let fields = &delta.players[0].fields;
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields["level"], 2);
+        let skills = fields["skills"].as_array().unwrap();
+        assert_eq!(skills.len(), 1);
+        assert_eq!(skills[0]["id"], 1);
+        assert_eq!(skills[0]["base"], 11);
+    }
+
+    #[test]
+    fn removed_players_are_listed() {
+        let mut state = DeltaState::default();
+        state.update(&[player(1, "Fargoth", 1), player(2, "Vivec", 50)]);
+
+        let delta = state.update(&[player(2, "Vivec", 50)]).unwrap();
+
+        assert_eq!(delta.seq, 2);
+        assert!(delta.players.is_empty());
+        assert_eq!(delta.removed, vec![1]);
+
+        let delta = state.update(&[]).unwrap();
+        assert_eq!(delta.seq, 3);
+        assert_eq!(delta.removed, vec![2]);
+    }
+
+    #[test]
+    fn seq_only_increments_on_changes() {
+        let mut state = DeltaState::default();
+        assert!(state.update(&[]).is_none());
+        assert_eq!(state.seq, 0);
+
+        state.update(&[player(1, "Fargoth", 1)]);
+        assert!(state.update(&[player(1, "Fargoth", 1)]).is_none());
+        assert_eq!(state.seq, 1);
+
+        let delta = state.update(&[player(1, "Fargoth", 2)]).unwrap();
+        assert_eq!(delta.seq, 2);
+        assert_eq!(state.seq, 2);
+    }
+
+    #[test]
+    fn snapshot_matches_last_update() {
+        let mut state = DeltaState::default();
+        state.update(&[player(1, "Fargoth", 1)]);
+        state.update(&[player(1, "Fargoth", 2)]);
+
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.seq, 2);
+        assert_eq!(snapshot.players.len(), 1);
+        assert_eq!(snapshot.players[0].level, 2);
+    }
+}
@@ -1,5 +1,17 @@
-use crate::server_info::player_details::Player;
+use crate::server_info::chat::ChatMessage;
+use crate::server_info::player_details::{Killer, Player, PlayerState, Vec3};
 use serde::Serialize;
+use serde_json::{Map, Value};
+use std::os::raw::{c_int, c_ushort};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Current unix time in seconds
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
 
 #[derive(Serialize, Clone, Debug)]
 #[serde(tag = "type")]
@@ -7,14 +19,249 @@ use serde::Serialize;
 pub enum WebsocketEvent {
     FullPlayer(FullPlayerEvent),
     PlayerPosition(PlayerPositionEvent),
+    PlayerDelta(PlayerDeltaEvent),
+    Hello(HelloEvent),
+    PlayerConnected(PlayerLifecycleEvent),
+    PlayerLoggedIn(PlayerLifecycleEvent),
+    PlayerDisconnected(PlayerLifecycleEvent),
+    PlayerDeath(PlayerDeathEvent),
+    PlayerCellChange(PlayerCellChangeEvent),
+    PlayerLevelUp(PlayerLevelUpEvent),
+    PlayerSkillIncrease(PlayerStatIncreaseEvent),
+    PlayerAttributeIncrease(PlayerStatIncreaseEvent),
+    ChatMessage(ChatMessage),
+}
+
+impl WebsocketEvent {
+    /// The topic clients subscribe to for this event
+    pub fn topic(&self) -> &'static str {
+        match self {
+            WebsocketEvent::PlayerPosition(_) => "positions",
+            _ => self.name(),
+        }
+    }
+
+    /// The `type` of this event, also used as SSE event name
+    pub fn name(&self) -> &'static str {
+        match self {
+            WebsocketEvent::FullPlayer(_) => "fullPlayer",
+            WebsocketEvent::PlayerPosition(_) => "playerPosition",
+            WebsocketEvent::PlayerDelta(_) => "playerDelta",
+            WebsocketEvent::Hello(_) => "hello",
+            WebsocketEvent::PlayerConnected(_) => "playerConnected",
+            WebsocketEvent::PlayerLoggedIn(_) => "playerLoggedIn",
+            WebsocketEvent::PlayerDisconnected(_) => "playerDisconnected",
+            WebsocketEvent::PlayerDeath(_) => "playerDeath",
+            WebsocketEvent::PlayerCellChange(_) => "playerCellChange",
+            WebsocketEvent::PlayerLevelUp(_) => "playerLevelUp",
+            WebsocketEvent::PlayerSkillIncrease(_) => "playerSkillIncrease",
+            WebsocketEvent::PlayerAttributeIncrease(_) => "playerAttributeIncrease",
+            WebsocketEvent::ChatMessage(_) => "chatMessage",
+        }
+    }
+
+    /// Lifecycle, delta and chat events are kept for `Last-Event-ID` resumption,
+    /// everything else is either outdated by the next tick or only interesting live
+    pub fn is_replayable(&self) -> bool {
+        matches!(
+            self,
+            WebsocketEvent::PlayerConnected(_)
+                | WebsocketEvent::PlayerLoggedIn(_)
+                | WebsocketEvent::PlayerDisconnected(_)
+                | WebsocketEvent::PlayerDelta(_)
+                | WebsocketEvent::ChatMessage(_)
+        )
+    }
+
+    /// Returns a copy of this event with only the players accepted by `filter`, or `None` if nothing is left
+    pub fn filter_players(&self, filter: impl Fn(&str, &str) -> bool) -> Option<WebsocketEvent> {
+        match self {
+            WebsocketEvent::FullPlayer(event) => {
+                let players: Vec<Player> = event
+                    .players
+                    .iter()
+                    .filter(|p| filter(&p.name, &p.cell))
+                    .cloned()
+                    .collect();
+
+                // Snapshots are always sent so clients notice when the last matching player left
+                Some(WebsocketEvent::FullPlayer(FullPlayerEvent {
+                    seq: event.seq,
+                    players,
+                }))
+            }
+            WebsocketEvent::PlayerPosition(event) => {
+                let positions: Vec<PlayerPosition> = event
+                    .positions
+                    .iter()
+                    .filter(|p| filter(&p.name, &p.cell))
+                    .cloned()
+                    .collect();
+
+                if positions.is_empty() {
+                    None
+                } else {
+                    Some(WebsocketEvent::PlayerPosition(PlayerPositionEvent {
+                        positions,
+                    }))
+                }
+            }
+            WebsocketEvent::PlayerDelta(event) => {
+                // Deltas are always sent so clients don't mistake a filtered delta for a gap
+                Some(WebsocketEvent::PlayerDelta(PlayerDeltaEvent {
+                    seq: event.seq,
+                    players: event
+                        .players
+                        .iter()
+                        .filter(|p| filter(&p.name, &p.cell))
+                        .cloned()
+                        .collect(),
+                    removed: event.removed.clone(),
+                }))
+            }
+            WebsocketEvent::Hello(event) => Some(WebsocketEvent::Hello(HelloEvent {
+                players: FullPlayerEvent {
+                    seq: event.players.seq,
+                    players: event
+                        .players
+                        .players
+                        .iter()
+                        .filter(|p| filter(&p.name, &p.cell))
+                        .cloned()
+                        .collect(),
+                },
+                ..event.clone()
+            })),
+            _ if self.concerns_players(filter) => Some(self.clone()),
+            _ => None,
+        }
+    }
+
+    /// Checks if an event about single players concerns any player accepted by `filter`
+    fn concerns_players(&self, filter: impl Fn(&str, &str) -> bool) -> bool {
+        match self {
+            WebsocketEvent::PlayerConnected(event)
+            | WebsocketEvent::PlayerLoggedIn(event)
+            | WebsocketEvent::PlayerDisconnected(event) => filter(&event.name, ""),
+            WebsocketEvent::PlayerLevelUp(event) => filter(&event.name, ""),
+            WebsocketEvent::PlayerSkillIncrease(event)
+            | WebsocketEvent::PlayerAttributeIncrease(event) => filter(&event.name, ""),
+            WebsocketEvent::PlayerDeath(event) => {
+                let killer_matches = match &event.killer {
+                    Killer::Player { name, .. } => filter(name, &event.cell),
+                    _ => false,
+                };
+
+                filter(&event.name, &event.cell) || killer_matches
+            }
+            WebsocketEvent::ChatMessage(event) => filter(&event.name, &event.cell),
+            WebsocketEvent::PlayerCellChange(event) => {
+                filter(&event.name, &event.from) || filter(&event.name, &event.to)
+            }
+            // Rewritten by filter_players() instead
+            WebsocketEvent::FullPlayer(_)
+            | WebsocketEvent::PlayerPosition(_)
+            | WebsocketEvent::PlayerDelta(_)
+            | WebsocketEvent::Hello(_) => true,
+        }
+    }
 }
 
 #[derive(Serialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct FullPlayerEvent {
+    pub seq: u64,
     pub players: Vec<Player>,
 }
 
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerLifecycleEvent {
+    pub id: c_ushort,
+    pub name: String,
+    pub state: PlayerState,
+    pub timestamp: u64,
+    /// Seconds since the player connected
+    pub session_duration: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerDeathEvent {
+    pub id: c_ushort,
+    pub name: String,
+    pub killer: Killer,
+    pub cell: String,
+    pub position: Vec3,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerCellChangeEvent {
+    pub id: c_ushort,
+    pub name: String,
+    pub from: String,
+    pub to: String,
+    pub is_outside: bool,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerLevelUpEvent {
+    pub id: c_ushort,
+    pub name: String,
+    pub old_level: c_int,
+    pub new_level: c_int,
+    pub timestamp: u64,
+}
+
+/// A skill or attribute base value went up
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerStatIncreaseEvent {
+    pub id: c_ushort,
+    pub name: String,
+    pub stat_id: c_ushort,
+    pub stat: String,
+    pub old_value: c_int,
+    pub new_value: c_int,
+    pub timestamp: u64,
+}
+
+/// Sent to every client right after connecting
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HelloEvent {
+    pub server_version: String,
+    pub plugin_version: String,
+    pub tick_rate: f64,
+    pub low_frequency_divisor: u64,
+    pub players: FullPlayerEvent,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerDeltaEvent {
+    pub seq: u64,
+    pub players: Vec<PlayerDelta>,
+    pub removed: Vec<c_ushort>,
+}
+
+/// The fields of a player that changed since the previous broadcast
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerDelta {
+    pub id: c_ushort,
+    #[serde(skip)]
+    pub name: String,
+    #[serde(skip)]
+    pub cell: String,
+    #[serde(flatten)]
+    pub fields: Map<String, Value>,
+}
+
 #[derive(Serialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct PlayerPositionEvent {
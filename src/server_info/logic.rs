@@ -1,32 +1,71 @@
-use crate::server_info::events::{FullPlayerEvent, PlayerPositionEvent, WebsocketEvent};
-use crate::server_info::player_details::Player;
-use futures_util::SinkExt;
-use std::collections::HashMap;
+use crate::config::CONFIG;
+use crate::server_info::bans::BanList;
+use crate::server_info::cells::{CellIndex, CellOccupancy};
+use crate::server_info::chat::{ChatLog, ChatMessage};
+use crate::server_info::clients::Client;
+use crate::server_info::commands::QueuedCommand;
+use crate::server_info::counters::{PLAYERS_LOGGED_IN, PLAYERS_ONLINE};
+use crate::server_info::delta::DeltaState;
+use crate::server_info::events::{timestamp, FullPlayerEvent, PlayerPositionEvent, WebsocketEvent};
+use crate::server_info::player_details::{Killer, Player, PlayerState};
+use crate::statsd::Batch;
+use std::collections::{HashMap, VecDeque};
 use std::future::Future;
 use std::os::raw::c_ushort;
 use std::sync::Arc;
 use tokio::runtime::{Handle, Runtime};
 use tokio::sync::{Mutex, RwLock};
-use warp::ws::{Message, WebSocket};
 
 #[derive(Default, Debug)]
 pub struct ServerInfo {
     pub players: HashMap<c_ushort, Player>,
+    pub delta: DeltaState,
+    pub cells: CellIndex,
+    pub chat: ChatLog,
 }
 
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct ServerLogic {
-    pub web_sockets: HashMap<u64, WebSocket>,
+    pub clients: HashMap<u64, Client>,
+    pub next_id: u64,
+    /// Id of the last published event, used for SSE event ids
+    pub event_id: u64,
+    /// Replayable events published after `evicted_event_id`
+    pub history: VecDeque<(u64, WebsocketEvent)>,
+    /// Id of the last event that was dropped from the history
+    pub evicted_event_id: u64,
+    /// How many replayable events are kept for resuming
+    pub history_size: usize,
+    /// How many events a resuming client may have missed, more wouldn't fit in its queue
+    pub queue_size: usize,
+}
+
+impl ServerLogic {
+    pub fn new(history_size: usize, queue_size: usize) -> Self {
+        ServerLogic {
+            clients: HashMap::new(),
+            next_id: 0,
+            event_id: 0,
+            history: VecDeque::with_capacity(history_size),
+            evicted_event_id: 0,
+            history_size,
+            queue_size,
+        }
+    }
 }
 
 type SyncMutex<T> = std::sync::Mutex<T>;
 
+/// Whoever needs both locks takes `info` before `logic`, events are published while holding `info`
 #[derive(Clone, Debug)]
 pub struct ServerInfoHandle {
     pub info: Arc<RwLock<ServerInfo>>,
     pub logic: Arc<Mutex<ServerLogic>>,
     pub runtime: Arc<SyncMutex<Runtime>>,
     pub handle: Arc<Handle>,
+    /// Filled by the HTTP thread, drained in `tick()`
+    pub commands: Arc<SyncMutex<VecDeque<QueuedCommand>>>,
+    pub bans: Arc<SyncMutex<BanList>>,
 }
 
 impl ServerInfoHandle {
@@ -36,53 +75,140 @@ impl ServerInfoHandle {
 
         ServerInfoHandle {
             info: Arc::new(RwLock::new(ServerInfo::default())),
-            logic: Arc::new(Mutex::new(ServerLogic::default())),
+            logic: Arc::new(Mutex::new(ServerLogic::new(
+                CONFIG.sse.history_size,
+                CONFIG.websocket.queue_size,
+            ))),
             runtime: Arc::new(SyncMutex::new(runtime)),
             handle: Arc::new(handle),
+            commands: Arc::new(SyncMutex::new(VecDeque::new())),
+            bans: Arc::new(SyncMutex::new(BanList::default())),
         }
     }
 
-    pub async fn publish_event(&self, event: WebsocketEvent) {
-        let logic = self.logic.clone();
-
-        self.handle.spawn(async move {
-            let mut logic = logic.lock().await;
-            let json = serde_json::to_string(&event).unwrap();
-
-            let mut to_remove = vec![];
-            for (id, web_socket) in &mut logic.web_sockets {
-                if web_socket.send(Message::text(&json)).await.is_err() {
-                    to_remove.push(*id);
-                }
+    pub async fn gui_action(&self, player_id: u16, _message_box_id: i32, _data: Option<&str>) {
+        let mut info = self.info.write().await;
+        if let Some(player) = info.players.get_mut(&player_id) {
+            // The login and register dialogs are the first GUI a player sees
+            if player.state == PlayerState::Connected {
+                player.set_state(PlayerState::Authenticating);
             }
+        }
+    }
 
-            for id in to_remove {
-                logic.web_sockets.remove(&id).map(|x| x.close());
+    /// Called once the character is loaded, either after character generation or on the first cell load
+    pub async fn enter_game(&self, player_id: c_ushort) {
+        let mut guard = self.info.write().await;
+        let info = &mut *guard;
+        if let Some(player) = info.players.get_mut(&player_id) {
+            if !matches!(
+                player.state,
+                PlayerState::Connected | PlayerState::Authenticating
+            ) {
+                return;
             }
-        });
+
+            // The level has to be loaded before on_login() makes it the baseline
+            player.update();
+            player.set_state(PlayerState::InGame);
+            player.on_login();
+            info.cells.enter(&player.cell, player_id);
+
+            let event = WebsocketEvent::PlayerLoggedIn(player.get_lifecycle_event());
+            self.publish_event(event).await;
+        }
     }
 
-    pub async fn gui_action(&self, player_id: u16, _message_box_id: i32, _data: Option<&str>) {
+    pub async fn player_death(&self, player_id: c_ushort) {
         let mut info = self.info.write().await;
-        if let Some(player) = info.players.get_mut(&player_id) {
-            if !player.logged_in {
-                player.logged_in = true;
-                player.on_login();
+        let killer = Killer::get(player_id);
+        let event = match info.players.get_mut(&player_id) {
+            Some(player) => player.on_death(killer),
+            None => return,
+        };
+
+        if let Killer::Player { id, .. } = &event.killer {
+            if *id != player_id {
+                if let Some(killer) = info.players.get_mut(id) {
+                    killer.on_kill();
+                }
             }
         }
+
+        self.publish_event(WebsocketEvent::PlayerDeath(event)).await;
+    }
+
+    pub async fn chat_message(&self, player_id: c_ushort, message: Option<&str>) {
+        let message = match message {
+            Some(message) => message,
+            None => return,
+        };
+
+        let mut guard = self.info.write().await;
+        let info = &mut *guard;
+        let player = match info.players.get(&player_id) {
+            Some(player) => player,
+            None => return,
+        };
+
+        let message = info.chat.push(ChatMessage {
+            id: 0,
+            player_id,
+            name: player.name.clone(),
+            cell: player.cell.clone(),
+            message: message.to_string(),
+            private: ChatMessage::is_private(message),
+            timestamp: timestamp(),
+        });
+
+        self.publish_event(WebsocketEvent::ChatMessage(message))
+            .await;
     }
 
     pub async fn update_players(&self, low_freq: bool) {
-        let mut info = self.info.write().await;
+        let mut guard = self.info.write().await;
+        let info = &mut *guard;
+        let online = info.players.len();
+        let logged_in = info.players.values().filter(|p| p.logged_in).count();
+        PLAYERS_ONLINE.set(online as i64);
+        PLAYERS_LOGGED_IN.set(logged_in as i64);
+
+        let mut statsd = Batch::default();
+        statsd.gauge("players.online", online as f64, &[]);
+        statsd.gauge("players.logged_in", logged_in as f64, &[]);
+
+        let mut events = vec![];
         for (_, player) in &mut info.players {
             if !player.logged_in {
                 continue;
             }
 
-            player.update();
+            if let Some(cell_change) = player.update() {
+                info.cells
+                    .move_player(&cell_change.from, &cell_change.to, player.id);
+                events.push(WebsocketEvent::PlayerCellChange(cell_change));
+            }
 
             if low_freq {
-                player.low_frequency_update();
+                events.extend(player.low_frequency_update());
+            }
+        }
+
+        for event in events {
+            self.publish_event(event).await;
+        }
+
+        if low_freq {
+            let players = info
+                .players
+                .values()
+                .filter(|p| p.logged_in)
+                .cloned()
+                .collect::<Vec<Player>>();
+
+            // Clients following `playerDelta` get what changed instead of the `fullPlayer` below
+            if let Some(delta) = info.delta.update(&players) {
+                self.publish_event(WebsocketEvent::PlayerDelta(delta)).await;
             }
         }
 
@@ -90,18 +216,12 @@ impl ServerInfoHandle {
             return;
         }
 
-        let players = info.players.clone();
         self.publish_event(if low_freq {
-            WebsocketEvent::FullPlayer(FullPlayerEvent {
-                players: players
-                    .values()
-                    .filter(|p| p.logged_in)
-                    .map(|p| p.clone())
-                    .collect::<Vec<Player>>(),
-            })
+            WebsocketEvent::FullPlayer(info.delta.snapshot())
         } else {
             WebsocketEvent::PlayerPosition(PlayerPositionEvent {
-                positions: players
+                positions: info
+                    .players
                     .values()
                     .filter(|p| p.logged_in)
                     .map(|p| p.get_player_position())
@@ -113,37 +233,70 @@ impl ServerInfoHandle {
 
     pub async fn add_player(&self, player_id: c_ushort) {
         let mut info = self.info.write().await;
-        info.players.insert(player_id, Player::new(player_id));
+        let player = Player::new(player_id);
+        self.publish_event(WebsocketEvent::PlayerConnected(
+            player.get_lifecycle_event(),
+        ))
+        .await;
+        info.players.insert(player_id, player);
     }
 
     pub async fn remove_player(&self, player: c_ushort) {
         let mut info = self.info.write().await;
-        info.players.remove(&player);
+        if let Some(mut player) = info.players.remove(&player) {
+            // Stats are only reported for players that logged in
+            if player.logged_in {
+                info.cells.leave(&player.cell, player.id);
+                player.remove_stats();
+            }
+
+            player.set_state(PlayerState::Disconnecting);
+            self.publish_event(WebsocketEvent::PlayerDisconnected(
+                player.get_lifecycle_event(),
+            ))
+            .await;
+        }
 
         if info.players.len() == 0 {
             self.publish_event(WebsocketEvent::FullPlayer(FullPlayerEvent {
+                seq: info.delta.seq,
                 players: vec![],
             }))
             .await;
         }
     }
 
+    pub async fn get_cells(&self) -> Vec<CellOccupancy> {
+        let info = self.info.read().await;
+
+        info.cells.get_cells(&info.players)
+    }
+
+    pub async fn get_chat(&self, since: u64, private: bool) -> Vec<ChatMessage> {
+        let info = self.info.read().await;
+
+        info.chat.since(since, private)
+    }
+
     pub async fn get_players(&self) -> Vec<Player> {
         let info = self.info.read().await;
 
         info.players.iter().map(|p| (*p.1).clone()).collect()
     }
 
-    pub async fn add_websocket(&self, ws: WebSocket) {
-        let mut logic = self.logic.lock().await;
-        let new_id = logic
-            .web_sockets
-            .keys()
-            .max()
-            .map(|x| x + 1)
-            .unwrap_or_default();
-        println!("Added websocket ({})", new_id);
-        logic.web_sockets.insert(new_id, ws);
+    pub async fn get_player(&self, player_id: c_ushort) -> Option<Player> {
+        let info = self.info.read().await;
+
+        info.players.get(&player_id).cloned()
+    }
+
+    pub async fn get_player_by_name(&self, name: &str) -> Option<Player> {
+        let info = self.info.read().await;
+
+        info.players
+            .values()
+            .find(|p| p.name.eq_ignore_ascii_case(name))
+            .cloned()
     }
 
     pub fn block_on<F: Future>(&mut self, task: F) -> F::Output {
@@ -1,8 +1,20 @@
+mod bans;
+mod cells;
+mod chat;
+mod clients;
+mod commands;
 mod counters;
+mod delta;
 mod events;
 mod logic;
+mod outbox;
 mod player_details;
+mod subscription;
 
-pub use events::*;
+pub use bans::*;
+pub use commands::*;
+pub use counters::*;
 pub use logic::*;
+pub use outbox::*;
 pub use player_details::*;
+pub use subscription::*;
@@ -0,0 +1,257 @@
+use crate::server_info::events::WebsocketEvent;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Notify;
+
+type SyncMutex<T> = std::sync::Mutex<T>;
+
+/// What to do when a client's outbound queue is full
+#[derive(Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued position frame, or the oldest other event if there is none
+    DropOldest,
+    /// Replace the queued position frame with the new one, drops like `DropOldest` otherwise
+    Coalesce,
+    /// Disconnect the client
+    Disconnect,
+}
+
+impl OverflowPolicy {
+    pub fn label(self) -> &'static str {
+        match self {
+            OverflowPolicy::DropOldest => "drop_oldest",
+            OverflowPolicy::Coalesce => "coalesce",
+            OverflowPolicy::Disconnect => "disconnect",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub id: u64,
+    pub event: &'static str,
+    pub topic: &'static str,
+    pub json: String,
+}
+
+impl Frame {
+    pub fn new(id: u64, event: &WebsocketEvent, json: String) -> Self {
+        Frame {
+            id,
+            event: event.name(),
+            topic: event.topic(),
+            json,
+        }
+    }
+
+    /// Hello, snapshots and deltas build on each other, losing one leaves the client with a wrong state
+    fn is_droppable(&self) -> bool {
+        !matches!(self.event, "hello" | "fullPlayer" | "playerDelta")
+    }
+}
+
+/// Bounded outbound queue of a single client, drained by its own writer task
+#[derive(Debug)]
+pub struct Outbox {
+    queue: SyncMutex<VecDeque<Frame>>,
+    notify: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// Called whenever the queue is full, before the policy is applied
+    on_overflow: fn(OverflowPolicy),
+    closed: AtomicBool,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, policy: OverflowPolicy, on_overflow: fn(OverflowPolicy)) -> Self {
+        Outbox {
+            queue: SyncMutex::new(VecDeque::with_capacity(capacity)),
+            notify: Notify::new(),
+            capacity,
+            policy,
+            on_overflow,
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Queues a frame, returns false if the client should be disconnected.
+    /// Closing the outbox ends `pop()`, which closes the websocket or ends the SSE stream
+    pub fn push(&self, frame: Frame) -> bool {
+        if self.is_closed() {
+            return false;
+        }
+
+        {
+            let mut queue = self.queue.lock().unwrap();
+            if queue.len() >= self.capacity {
+                (self.on_overflow)(self.policy);
+
+                if self.policy == OverflowPolicy::Coalesce && frame.topic == "positions" {
+                    if let Some(queued) = queue.iter_mut().find(|f| f.topic == "positions") {
+                        *queued = frame;
+                        return true;
+                    }
+                }
+
+                let index = match self.policy {
+                    OverflowPolicy::Disconnect => None,
+                    OverflowPolicy::DropOldest | OverflowPolicy::Coalesce => queue
+                        .iter()
+                        .position(|f| f.topic == "positions")
+                        .or_else(|| queue.iter().position(Frame::is_droppable)),
+                };
+
+                match index {
+                    Some(index) => {
+                        queue.remove(index);
+                    }
+                    // Dropping the new frame keeps the client consistent, it only misses this event
+                    None if frame.is_droppable() && self.policy != OverflowPolicy::Disconnect => {
+                        return true;
+                    }
+                    None => {
+                        drop(queue);
+                        self.close();
+                        return false;
+                    }
+                }
+            }
+
+            queue.push_back(frame);
+        }
+
+        self.notify.notify();
+        true
+    }
+
+    /// Waits for queued frames, returns `None` once the outbox is closed
+    pub async fn pop(&self) -> Option<Vec<Frame>> {
+        loop {
+            if self.is_closed() {
+                return None;
+            }
+
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if !queue.is_empty() {
+                    return Some(queue.drain(..).collect());
+                }
+            }
+
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.notify.notify();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn frame(id: u64, event: &'static str) -> Frame {
+        Frame {
+            id,
+            event,
+            topic: if event == "playerPosition" {
+                "positions"
+            } else {
+                event
+            },
+            json: String::new(),
+        }
+    }
+
+    fn queued(outbox: &Outbox) -> Vec<u64> {
+        outbox.queue.lock().unwrap().iter().map(|f| f.id).collect()
+    }
+
+    #[test]
+    fn drop_oldest_drops_positions_first() {
+        let outbox = Outbox::new(3, OverflowPolicy::DropOldest, |_| {});
+        assert!(outbox.push(frame(1, "hello")));
+        assert!(outbox.push(frame(2, "playerPosition")));
+        assert!(outbox.push(frame(3, "chatMessage")));
+
+        assert!(outbox.push(frame(4, "playerDelta")));
+        assert_eq!(queued(&outbox), vec![1, 3, 4]);
+
+        assert!(outbox.push(frame(5, "playerDelta")));
+        assert_eq!(queued(&outbox), vec![1, 4, 5]);
+    }
+
+    #[test]
+    fn coalesce_replaces_queued_positions() {
+        let outbox = Outbox::new(3, OverflowPolicy::Coalesce, |_| {});
+        assert!(outbox.push(frame(1, "hello")));
+        assert!(outbox.push(frame(2, "playerPosition")));
+        assert!(outbox.push(frame(3, "playerDelta")));
+
+        assert!(outbox.push(frame(4, "playerPosition")));
+        assert_eq!(queued(&outbox), vec![1, 4, 3]);
+
+        assert!(outbox.push(frame(5, "playerDelta")));
+        assert_eq!(queued(&outbox), vec![1, 3, 5]);
+    }
+
+    #[test]
+    fn never_drops_hello_or_deltas() {
+        for policy in &[OverflowPolicy::DropOldest, OverflowPolicy::Coalesce] {
+            let outbox = Outbox::new(2, *policy, |_| {});
+            assert!(outbox.push(frame(1, "hello")));
+            assert!(outbox.push(frame(2, "playerDelta")));
+
+            // Other events are dropped instead
+            assert!(outbox.push(frame(3, "playerPosition")));
+            assert!(outbox.push(frame(4, "chatMessage")));
+            assert_eq!(queued(&outbox), vec![1, 2]);
+            assert!(!outbox.is_closed());
+
+            assert!(!outbox.push(frame(5, "playerDelta")));
+            assert!(outbox.is_closed());
+        }
+    }
+
+    #[test]
+    fn disconnect_closes_the_outbox() {
+        let outbox = Outbox::new(1, OverflowPolicy::Disconnect, |_| {});
+        assert!(outbox.push(frame(1, "playerPosition")));
+        assert!(!outbox.push(frame(2, "playerPosition")));
+        assert!(outbox.is_closed());
+        assert!(!outbox.push(frame(3, "playerPosition")));
+    }
+
+    #[tokio::test]
+    async fn pop_ends_once_closed() {
+        let outbox = Outbox::new(2, OverflowPolicy::Disconnect, |_| {});
+        outbox.push(frame(1, "hello"));
+        assert_eq!(outbox.pop().await.unwrap().len(), 1);
+
+        outbox.close();
+        assert!(outbox.pop().await.is_none());
+    }
+
+    #[test]
+    fn reports_overflows() {
+        static OVERFLOWS: AtomicUsize = AtomicUsize::new(0);
+
+        let outbox = Outbox::new(1, OverflowPolicy::DropOldest, |policy| {
+            assert_eq!(policy, OverflowPolicy::DropOldest);
+            OVERFLOWS.fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(outbox.push(frame(1, "playerPosition")));
+        assert_eq!(OVERFLOWS.load(Ordering::SeqCst), 0);
+        assert!(outbox.push(frame(2, "playerPosition")));
+        assert_eq!(OVERFLOWS.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,12 +1,18 @@
+use crate::config::CONFIG;
 use crate::plugin::*;
 use crate::server_info::counters::*;
-use crate::server_info::events::PlayerPosition;
+use crate::server_info::events::{
+    timestamp, PlayerCellChangeEvent, PlayerDeathEvent, PlayerLevelUpEvent, PlayerLifecycleEvent,
+    PlayerPosition, PlayerStatIncreaseEvent, WebsocketEvent,
+};
 use crate::server_info::Specialization::{Combat, Magic, Stealth};
-use serde::Serialize;
+use crate::statsd;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::{HashMap, HashSet};
 use std::os::raw::{c_double, c_int, c_ushort};
 
-#[derive(Serialize, Debug, Copy, Clone, Default, PartialOrd, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default, PartialOrd, PartialEq)]
 pub struct Vec3 {
     pub x: c_double,
     pub y: c_double,
@@ -223,6 +229,56 @@ impl Vec3 {
     }
 }
 
+/// Who or what killed a player
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "camelCase")]
+pub enum Killer {
+    #[serde(rename_all = "camelCase")]
+    Player {
+        id: c_ushort,
+        name: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Npc {
+        ref_id: String,
+        name: String,
+    },
+    None,
+}
+
+impl Killer {
+    pub fn get(player_id: c_ushort) -> Killer {
+        if does_player_have_player_killer(player_id) {
+            return Killer::Player {
+                id: get_player_killer_pid(player_id) as c_ushort,
+                name: get_player_killer_name(player_id),
+            };
+        }
+
+        let ref_id = get_player_killer_ref_id(player_id);
+        if ref_id.is_empty() {
+            Killer::None
+        } else {
+            Killer::Npc {
+                ref_id,
+                name: get_player_killer_name(player_id),
+            }
+        }
+    }
+}
+
+/// Where a player is in the connect → login → play → leave cycle
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum PlayerState {
+    #[default]
+    Connected,
+    Authenticating,
+    InGame,
+    Disconnecting,
+}
+
 #[derive(Serialize, Clone, Debug, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Player {
@@ -230,7 +286,9 @@ pub struct Player {
     pub name: String,
     pub head: String,
     pub hair: String,
+    pub state: PlayerState,
     pub logged_in: bool,
+    /// Distance travelled this session, teleports excluded
     pub distance_travelled: f64,
     pub race: String,
     pub class: PlayerClass,
@@ -251,6 +309,19 @@ pub struct Player {
     pub major_skills: HashSet<c_ushort>,
     pub minor_skills: HashSet<c_ushort>,
     pub specialisation: Specialization,
+    pub connected_at: u64,
+    pub deaths: u64,
+    pub kills: u64,
+    #[serde(skip)]
+    pub reported_level: c_int,
+    /// Whether the current stats were read in game, the first ones after logging in are the loaded character
+    #[serde(skip)]
+    pub stats_in_game: bool,
+    /// Distance travelled since the last `report_stats`
+    #[serde(skip)]
+    pub unreported_distance: f64,
+    #[serde(skip)]
+    pub ip: String,
 }
 
 #[derive(Serialize, Clone, Debug)]
@@ -268,10 +339,31 @@ impl Default for PlayerClass {
     }
 }
 
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerLocation {
+    pub cell: String,
+    pub is_outside: bool,
+    pub position: Vec3,
+    pub rotation: Vec3,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerClassInfo {
+    pub class: PlayerClass,
+    pub specialisation: Specialization,
+    pub major_skills: HashSet<c_ushort>,
+    pub minor_skills: HashSet<c_ushort>,
+}
+
 impl Player {
     pub fn new(id: c_ushort) -> Self {
         let mut player = Player::default();
         player.id = id;
+        player.name = get_name(id);
+        player.ip = get_ip(id);
+        player.connected_at = timestamp();
         player.update();
         player.low_frequency_update();
 
@@ -288,6 +380,66 @@ impl Player {
         }
     }
 
+    /// Moves the player to `state`, `logged_in` is kept for API compatibility
+    pub fn set_state(&mut self, state: PlayerState) {
+        self.state = state;
+        self.logged_in = state == PlayerState::InGame;
+    }
+
+    pub fn get_lifecycle_event(&self) -> PlayerLifecycleEvent {
+        let now = timestamp();
+
+        PlayerLifecycleEvent {
+            id: self.id,
+            name: self.name.clone(),
+            state: self.state,
+            timestamp: now,
+            session_duration: now.saturating_sub(self.connected_at),
+        }
+    }
+
+    pub fn on_death(&mut self, killer: Killer) -> PlayerDeathEvent {
+        self.deaths += 1;
+        let label = self.metric_label();
+        DEATHS.with_label_values(&[&label]).inc();
+        statsd::count("player.deaths", 1.0, &[("player", &label)]);
+
+        // Not using update() here so the next tick still notices a cell change
+        PlayerDeathEvent {
+            id: self.id,
+            name: self.name.clone(),
+            killer,
+            cell: get_cell(self.id),
+            position: Vec3::get_position(self.id),
+            timestamp: timestamp(),
+        }
+    }
+
+    pub fn on_kill(&mut self) {
+        self.kills += 1;
+        let label = self.metric_label();
+        KILLS.with_label_values(&[&label]).inc();
+        statsd::count("player.kills", 1.0, &[("player", &label)]);
+    }
+
+    pub fn get_location(&self) -> PlayerLocation {
+        PlayerLocation {
+            cell: self.cell.clone(),
+            is_outside: self.is_outside,
+            position: self.position,
+            rotation: self.rotation,
+        }
+    }
+
+    pub fn get_class_info(&self) -> PlayerClassInfo {
+        PlayerClassInfo {
+            class: self.class.clone(),
+            specialisation: self.specialisation,
+            major_skills: self.major_skills.clone(),
+            minor_skills: self.minor_skills.clone(),
+        }
+    }
+
     pub fn get_skill_type(&self, skill_id: c_ushort) -> SkillType {
         if self.major_skills.contains(&skill_id) {
             SkillType::Major
@@ -298,19 +450,41 @@ impl Player {
         }
     }
 
-    pub fn update(&mut self) {
+    /// Refreshes the frequently changing stats, returns the cell transition if the player changed cells
+    pub fn update(&mut self) -> Option<PlayerCellChangeEvent> {
         self.rotation = Vec3::get_rotation(self.id);
+        let was_outside = self.is_outside;
         self.is_outside = is_in_exterior(self.id);
 
         let cell = get_cell(self.id);
         let position = Vec3::get_position(self.id);
 
-        if cell == self.cell {
-            self.distance_travelled += self.position.distance(position)
+        // Exterior cells share one coordinate space, every interior has its own
+        if !self.cell.is_empty() && (cell == self.cell || (was_outside && self.is_outside)) {
+            let distance = self.position.distance(position);
+
+            // Anything faster is a teleport, recall or a door
+            if distance <= CONFIG.max_distance_per_tick {
+                self.distance_travelled += distance;
+                self.unreported_distance += distance;
+            }
         }
 
         self.position = position;
 
+        let cell_change = if cell != self.cell && !self.cell.is_empty() {
+            Some(PlayerCellChangeEvent {
+                id: self.id,
+                name: self.name.clone(),
+                from: self.cell.clone(),
+                to: cell.clone(),
+                is_outside: self.is_outside,
+                timestamp: timestamp(),
+            })
+        } else {
+            None
+        };
+
         self.cell = cell;
         self.health_base = get_health_base(self.id);
         self.health = get_health_current(self.id);
@@ -319,7 +493,9 @@ impl Player {
         self.magicka_base = get_magicka_base(self.id);
         self.magicka = get_magicka_current(self.id);
         self.level = get_level(self.id);
-        self.level_progress = get_level_progress(self.id)
+        self.level_progress = get_level_progress(self.id);
+
+        cell_change
     }
 
     pub fn update_once(&mut self) {
@@ -362,11 +538,27 @@ impl Player {
         }
     }
 
-    pub fn low_frequency_update(&mut self) {
-        self.attributes = (0..get_attribute_count() as c_ushort)
+    /// Refreshes skills and attributes, returns the level, skill and attribute increases since the previous call
+    pub fn low_frequency_update(&mut self) -> Vec<WebsocketEvent> {
+        let (attributes, skills) = self.get_stats();
+        let changes = self.apply_stats(attributes, skills);
+
+        if self.logged_in {
+            self.report_stats();
+
+            for change in &changes {
+                self.announce(change);
+            }
+        }
+
+        changes
+    }
+
+    fn get_stats(&self) -> (Vec<AttributeValue>, Vec<SkillValue>) {
+        let attributes: Vec<AttributeValue> = (0..get_attribute_count() as c_ushort)
             .map(|id| AttributeValue::get(self.id, id))
             .collect();
-        self.skills = (0..get_skill_count() as c_ushort)
+        let skills: Vec<SkillValue> = (0..get_skill_count() as c_ushort)
             .map(|id| {
                 let mut skill = SkillValue::get(self.id, id);
                 skill.calculate_progress(
@@ -378,54 +570,363 @@ impl Player {
             })
             .collect();
 
-        if self.logged_in {
-            self.report_stats();
+        (attributes, skills)
+    }
+
+    /// The level, skill and attribute increases compared to the previous `apply_stats`
+    fn stat_increases(
+        &self,
+        attributes: &[AttributeValue],
+        skills: &[SkillValue],
+    ) -> Vec<WebsocketEvent> {
+        let mut changes = vec![];
+        if self.reported_level != 0 && self.level > self.reported_level {
+            changes.push(WebsocketEvent::PlayerLevelUp(PlayerLevelUpEvent {
+                id: self.id,
+                name: self.name.clone(),
+                old_level: self.reported_level,
+                new_level: self.level,
+                timestamp: timestamp(),
+            }));
         }
+
+        for skill in skills {
+            if let Some(old) = self.skills.iter().find(|old| old.id == skill.id) {
+                if skill.base > old.base {
+                    changes.push(WebsocketEvent::PlayerSkillIncrease(self.get_stat_increase(
+                        skill.id,
+                        &skill.name,
+                        old.base,
+                        skill.base,
+                    )));
+                }
+            }
+        }
+
+        for attribute in attributes {
+            if let Some(old) = self.attributes.iter().find(|old| old.id == attribute.id) {
+                if attribute.base > old.base {
+                    changes.push(WebsocketEvent::PlayerAttributeIncrease(
+                        self.get_stat_increase(
+                            attribute.id,
+                            &attribute.name,
+                            old.base,
+                            attribute.base,
+                        ),
+                    ));
+                }
+            }
+        }
+
+        changes
+    }
+
+    /// Makes the current level and the given stats the baseline for the next call, returns the increases
+    /// since the previous one. Stats read before being in game are still loading, so they never count
+    fn apply_stats(
+        &mut self,
+        attributes: Vec<AttributeValue>,
+        skills: Vec<SkillValue>,
+    ) -> Vec<WebsocketEvent> {
+        let changes = if self.stats_in_game {
+            self.stat_increases(&attributes, &skills)
+        } else {
+            vec![]
+        };
+
+        self.reported_level = self.level;
+        self.attributes = attributes;
+        self.skills = skills;
+        self.stats_in_game = self.logged_in;
+
+        changes
+    }
+
+    fn get_stat_increase(
+        &self,
+        stat_id: c_ushort,
+        stat: &str,
+        old_value: c_int,
+        new_value: c_int,
+    ) -> PlayerStatIncreaseEvent {
+        PlayerStatIncreaseEvent {
+            id: self.id,
+            name: self.name.clone(),
+            stat_id,
+            stat: stat.to_string(),
+            old_value,
+            new_value,
+            timestamp: timestamp(),
+        }
+    }
+
+    /// Sends an in-game message about the change to everyone, if enabled in the config
+    fn announce(&self, change: &WebsocketEvent) {
+        let announcements = &CONFIG.announcements;
+        let message = match change {
+            WebsocketEvent::PlayerLevelUp(event) if announcements.level_up => {
+                format!("#ffd700{} reached level {}!\n", event.name, event.new_level)
+            }
+            WebsocketEvent::PlayerSkillIncrease(event) if announcements.skill_increase => {
+                format!(
+                    "#ffd700{}'s {} increased to {}\n",
+                    event.name, event.stat, event.new_value
+                )
+            }
+            WebsocketEvent::PlayerAttributeIncrease(event) if announcements.attribute_increase => {
+                format!(
+                    "#ffd700{}'s {} increased to {}\n",
+                    event.name, event.stat, event.new_value
+                )
+            }
+            _ => return,
+        };
+
+        send_message(self.id, &message, true, false);
+    }
+
+    /// The `player` label of this player's metrics, the name or a salted hash of it
+    pub fn metric_label(&self) -> String {
+        let metrics = &CONFIG.metrics;
+        if !metrics.hash_player_names {
+            return self.name.clone();
+        }
+
+        let hash = Sha256::digest(format!("{}{}", metrics.hash_salt, self.name).as_bytes());
+        hash.iter().take(8).map(|b| format!("{:02x}", b)).collect()
     }
 
+    /// Updates the Prometheus metrics and sends the same values to StatsD
     pub fn report_stats(&mut self) {
+        let label = self.metric_label();
+        let mut statsd = statsd::Batch::default();
         for skill in &self.skills {
             let mut map = HashMap::new();
-            map.insert("player", self.name.as_str());
+            map.insert("player", label.as_str());
             map.insert("skill", &skill.name.as_str());
             let id = skill.id.to_string();
             map.insert("skill_id", &id);
             SKILL_LEVEL.with(&map).set(skill.base as i64);
             SKILL_PROGRESS.with(&map).set(skill.progress_percent);
+
+            let tags = [
+                ("player", label.as_str()),
+                ("skill", skill.name.as_str()),
+                ("skill_id", id.as_str()),
+            ];
+            statsd.gauge("player.skill_level", skill.base as f64, &tags);
+            statsd.gauge("player.skill_progress", skill.progress_percent, &tags);
         }
 
         for attribute in &self.attributes {
             let mut map = HashMap::new();
-            map.insert("player", self.name.as_str());
+            map.insert("player", label.as_str());
             map.insert("attribute", &attribute.name.as_str());
             let id = attribute.id.to_string();
             map.insert("attribute_id", &id);
             ATTRIBUTE_LEVEL.with(&map).set(attribute.base as i64);
+
+            let tags = [
+                ("player", label.as_str()),
+                ("attribute", attribute.name.as_str()),
+                ("attribute_id", id.as_str()),
+            ];
+            statsd.gauge("player.attr_level", attribute.base as f64, &tags);
         }
         let mut map = HashMap::new();
-        map.insert("player", self.name.as_str());
+        map.insert("player", label.as_str());
+        let tags = [("player", label.as_str())];
 
         LEVEL.with(&map).set(self.level as i64);
         LEVEL_PROGRESS.with(&map).set(self.level_progress as i64);
+        statsd.gauge("player.level", self.level as f64, &tags);
+        statsd.gauge("player.level_progress", self.level_progress as f64, &tags);
 
         MAGICKA_BASE.with(&map).set(self.magicka_base);
         MAGICKA.with(&map).set(self.magicka);
+        statsd.gauge("player.magicka_base", self.magicka_base, &tags);
+        statsd.gauge("player.magicka", self.magicka, &tags);
 
         HEALTH_BASE.with(&map).set(self.health_base);
         HEALTH.with(&map).set(self.health);
+        statsd.gauge("player.health_base", self.health_base, &tags);
+        statsd.gauge("player.health", self.health, &tags);
 
         FATIGUE_BASE.with(&map).set(self.fatigue_base);
         FATIGUE.with(&map).set(self.fatigue);
+        statsd.gauge("player.fatigue_base", self.fatigue_base, &tags);
+        statsd.gauge("player.fatigue", self.fatigue, &tags);
 
-        DISTANCE_TRAVELED
-            .with(&map)
-            .observe(self.distance_travelled);
-        self.distance_travelled = 0.0;
+        DISTANCE.with(&map).inc_by(self.unreported_distance);
+        if self.unreported_distance > 0.0 {
+            statsd.count("player.distance", self.unreported_distance, &tags);
+        }
+        self.unreported_distance = 0.0;
+    }
+
+    /// Removes every series labeled with this player, called when they disconnect.
+    /// StatsD keeps the last value of a gauge around, so those go to 0 instead
+    pub fn remove_stats(&self) {
+        let label = self.metric_label();
+        let mut statsd = statsd::Batch::default();
+        for skill in &self.skills {
+            let mut map = HashMap::new();
+            map.insert("player", label.as_str());
+            map.insert("skill", skill.name.as_str());
+            let id = skill.id.to_string();
+            map.insert("skill_id", &id);
+            let _ = SKILL_LEVEL.remove(&map);
+            let _ = SKILL_PROGRESS.remove(&map);
+
+            let tags = [
+                ("player", label.as_str()),
+                ("skill", skill.name.as_str()),
+                ("skill_id", id.as_str()),
+            ];
+            statsd.gauge("player.skill_level", 0.0, &tags);
+            statsd.gauge("player.skill_progress", 0.0, &tags);
+        }
+
+        for attribute in &self.attributes {
+            let mut map = HashMap::new();
+            map.insert("player", label.as_str());
+            map.insert("attribute", attribute.name.as_str());
+            let id = attribute.id.to_string();
+            map.insert("attribute_id", &id);
+            let _ = ATTRIBUTE_LEVEL.remove(&map);
+
+            let tags = [
+                ("player", label.as_str()),
+                ("attribute", attribute.name.as_str()),
+                ("attribute_id", id.as_str()),
+            ];
+            statsd.gauge("player.attr_level", 0.0, &tags);
+        }
+
+        let tags = [("player", label.as_str())];
+        for name in &[
+            "player.level",
+            "player.level_progress",
+            "player.magicka_base",
+            "player.magicka",
+            "player.health_base",
+            "player.health",
+            "player.fatigue_base",
+            "player.fatigue",
+        ] {
+            statsd.gauge(name, 0.0, &tags);
+        }
+
+        let values = [label.as_str()];
+        let _ = LEVEL.remove_label_values(&values);
+        let _ = LEVEL_PROGRESS.remove_label_values(&values);
+        let _ = MAGICKA_BASE.remove_label_values(&values);
+        let _ = MAGICKA.remove_label_values(&values);
+        let _ = HEALTH_BASE.remove_label_values(&values);
+        let _ = HEALTH.remove_label_values(&values);
+        let _ = FATIGUE_BASE.remove_label_values(&values);
+        let _ = FATIGUE.remove_label_values(&values);
+        let _ = DEATHS.remove_label_values(&values);
+        let _ = KILLS.remove_label_values(&values);
+        let _ = DISTANCE.remove_label_values(&values);
     }
 
     pub fn on_login(&mut self) {
-        send_message(self.id, "#ff0000This server runs #0000ffnwahttp#ff0000 and this is it's obnoxious login message for #00ff00you#ff0000!!\n", false, false);
+        if !CONFIG.login_message.is_empty() {
+            send_message(self.id, &CONFIG.login_message, false, false);
+        }
         self.update_once();
         self.low_frequency_update();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn skill(id: c_ushort, base: c_int) -> SkillValue {
+        SkillValue {
+            id,
+            base,
+            ..SkillValue::default()
+        }
+    }
+
+    fn attribute(id: c_ushort, base: c_int) -> AttributeValue {
+        AttributeValue {
+            id,
+            base,
+            ..AttributeValue::default()
+        }
+    }
+
+    /// A player as seen while connecting, before the character is loaded
+    fn connecting_player() -> Player {
+        let mut player = Player {
+            level: 1,
+            ..Player::default()
+        };
+        assert!(player
+            .apply_stats(vec![attribute(0, 40)], vec![skill(0, 5)])
+            .is_empty());
+        player
+    }
+
+    /// Logs in with the loaded character, like `on_login` does
+    fn logged_in_player() -> Player {
+        let mut player = connecting_player();
+        player.level = 20;
+        player.set_state(PlayerState::InGame);
+        let changes = player.apply_stats(vec![attribute(0, 80)], vec![skill(0, 60)]);
+        assert!(changes.is_empty(), "unexpected {:?}", changes);
+        player
+    }
+
+    #[test]
+    fn loading_the_character_is_no_increase() {
+        let player = logged_in_player();
+
+        assert!(player.stats_in_game);
+        assert_eq!(player.reported_level, 20);
+        assert_eq!(player.skills[0].base, 60);
+        assert_eq!(player.attributes[0].base, 80);
+    }
+
+    #[test]
+    fn reports_increases_after_login() {
+        let mut player = logged_in_player();
+
+        player.level = 21;
+        let changes = player.apply_stats(vec![attribute(0, 81)], vec![skill(0, 61)]);
+        let names: Vec<&str> = changes.iter().map(|c| c.name()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "playerLevelUp",
+                "playerSkillIncrease",
+                "playerAttributeIncrease"
+            ]
+        );
+
+        match &changes[0] {
+            WebsocketEvent::PlayerLevelUp(event) => {
+                assert_eq!(event.old_level, 20);
+                assert_eq!(event.new_level, 21);
+            }
+            _ => unreachable!(),
+        }
+
+        assert!(player
+            .apply_stats(vec![attribute(0, 81)], vec![skill(0, 61)])
+            .is_empty());
+    }
+
+    #[test]
+    fn decreases_are_not_increases() {
+        let mut player = logged_in_player();
+
+        assert!(player
+            .apply_stats(vec![attribute(0, 30)], vec![skill(0, 4)])
+            .is_empty());
+    }
+}
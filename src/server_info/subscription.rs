@@ -0,0 +1,417 @@
+use crate::server_info::events::WebsocketEvent;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+/// A topic a websocket client can subscribe to, e.g. `positions`, `player:Fargoth` or `cell:Balmora`
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Topic {
+    Event(String),
+    Player(String),
+    Cell(String),
+}
+
+impl Topic {
+    pub fn parse(topic: &str) -> Topic {
+        if let Some(name) = topic.strip_prefix("player:") {
+            Topic::Player(name.to_lowercase())
+        } else if let Some(cell) = topic.strip_prefix("cell:") {
+            Topic::Cell(cell.to_lowercase())
+        } else {
+            Topic::Event(topic.to_string())
+        }
+    }
+}
+
+/// Messages a websocket client can send to the server
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ClientMessage {
+    pub subscribe: Vec<String>,
+    pub unsubscribe: Vec<String>,
+    pub resync: bool,
+}
+
+impl ClientMessage {
+    /// Clients get a fresh snapshot when they ask for a resync or start following deltas
+    pub fn wants_snapshot(&self) -> bool {
+        self.resync || self.subscribe.iter().any(|topic| topic == "playerDelta")
+    }
+}
+
+/// The topics a client is subscribed to, `None` means the client never subscribed and gets everything
+#[derive(Clone, Debug, Default)]
+pub struct Subscription {
+    topics: Option<HashSet<Topic>>,
+    /// Private chat messages are only sent to authenticated clients
+    private_chat: bool,
+}
+
+impl Subscription {
+    /// Creates a subscription from a comma separated list of topics, used by the SSE endpoint
+    pub fn from_topics(topics: &str) -> Subscription {
+        let mut subscription = Subscription::default();
+        subscription.apply(&ClientMessage {
+            subscribe: topics
+                .split(',')
+                .map(|topic| topic.trim())
+                .filter(|topic| !topic.is_empty())
+                .map(|topic| topic.to_string())
+                .collect(),
+            ..ClientMessage::default()
+        });
+
+        subscription
+    }
+
+    pub fn private_chat(mut self, private_chat: bool) -> Subscription {
+        self.private_chat = private_chat;
+        self
+    }
+
+    pub fn apply(&mut self, message: &ClientMessage) {
+        if message.subscribe.is_empty() && message.unsubscribe.is_empty() {
+            return;
+        }
+
+        let topics = self.topics.get_or_insert_with(HashSet::new);
+        for topic in &message.subscribe {
+            topics.insert(Topic::parse(topic));
+        }
+
+        for topic in &message.unsubscribe {
+            topics.remove(&Topic::parse(topic));
+        }
+    }
+
+    /// `playerDelta` is opt-in, clients following it get deltas instead of the periodic `fullPlayer`
+    pub fn follows_deltas(&self) -> bool {
+        self.topics
+            .as_ref()
+            .is_some_and(|topics| topics.contains(&Topic::Event("playerDelta".to_string())))
+    }
+
+    pub fn wants_event(&self, topic: &str) -> bool {
+        match topic {
+            "playerDelta" => return self.follows_deltas(),
+            "fullPlayer" if self.follows_deltas() => return false,
+            _ => {}
+        }
+
+        match &self.topics {
+            None => true,
+            Some(topics) => {
+                let mut events = topics
+                    .iter()
+                    .filter_map(|topic| match topic {
+                        Topic::Event(event) => Some(event),
+                        _ => None,
+                    })
+                    .peekable();
+
+                // Only player or cell topics means every event for those players
+                if events.peek().is_none() {
+                    return self.has_player_filter();
+                }
+
+                events.any(|event| event == topic)
+            }
+        }
+    }
+
+    pub fn has_player_filter(&self) -> bool {
+        self.topics.as_ref().is_some_and(|topics| {
+            topics
+                .iter()
+                .any(|topic| matches!(topic, Topic::Player(_) | Topic::Cell(_)))
+        })
+    }
+
+    /// Checks the player and, if given, the cell topics, without any of them every player matches
+    pub fn wants_player(&self, name: &str, cell: Option<&str>) -> bool {
+        let topics = match &self.topics {
+            Some(topics) => topics,
+            None => return true,
+        };
+
+        let name = name.to_lowercase();
+        let cell = cell.map(|cell| cell.to_lowercase());
+        let mut filtered = false;
+        for topic in topics {
+            let matches = match (topic, &cell) {
+                (Topic::Player(player), _) => *player == name,
+                (Topic::Cell(topic_cell), Some(cell)) => topic_cell == cell,
+                _ => continue,
+            };
+
+            if matches {
+                return true;
+            }
+            filtered = true;
+        }
+
+        !filtered
+    }
+
+    /// Returns the event as this client should see it, or `None` if it isn't subscribed to it
+    pub fn filter<'a>(&self, event: &'a WebsocketEvent) -> Option<Cow<'a, WebsocketEvent>> {
+        if !self.wants_event(event.topic()) {
+            return None;
+        }
+
+        if let WebsocketEvent::ChatMessage(message) = event {
+            if message.private && !self.private_chat {
+                return None;
+            }
+        }
+
+        self.filter_players(event)
+    }
+
+    /// Like `filter` but only applies the player and cell topics, used for snapshots the client asked for
+    pub fn filter_players<'a>(&self, event: &'a WebsocketEvent) -> Option<Cow<'a, WebsocketEvent>> {
+        if !self.has_player_filter() {
+            return Some(Cow::Borrowed(event));
+        }
+
+        // Deltas only list players as removed when they disconnect, so a player leaving a cell would
+        // be stuck in the client's list. Cell topics don't apply to deltas and the snapshots they build on
+        let cells = !(self.follows_deltas()
+            && matches!(
+                event,
+                WebsocketEvent::FullPlayer(_)
+                    | WebsocketEvent::PlayerDelta(_)
+                    | WebsocketEvent::Hello(_)
+            ));
+
+        event
+            .filter_players(|name, cell| self.wants_player(name, Some(cell).filter(|_| cells)))
+            .map(Cow::Owned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_info::chat::ChatMessage;
+    use crate::server_info::events::{
+        PlayerDelta, PlayerDeltaEvent, PlayerLifecycleEvent, PlayerPosition, PlayerPositionEvent,
+    };
+    use crate::server_info::player_details::PlayerState;
+
+    fn subscribe(topics: &[&str]) -> Subscription {
+        let mut subscription = Subscription::default();
+        subscription.apply(&ClientMessage {
+            subscribe: topics.iter().map(|topic| topic.to_string()).collect(),
+            ..ClientMessage::default()
+        });
+        subscription
+    }
+
+    fn position(name: &str, cell: &str) -> PlayerPosition {
+        PlayerPosition {
+            name: name.to_string(),
+            position: (0.0, 0.0),
+            rotation: 0.0,
+            cell: cell.to_string(),
+            is_outside: false,
+        }
+    }
+
+    fn connected(name: &str) -> WebsocketEvent {
+        WebsocketEvent::PlayerConnected(PlayerLifecycleEvent {
+            id: 1,
+            name: name.to_string(),
+            state: PlayerState::Connected,
+            timestamp: 0,
+            session_duration: 0,
+        })
+    }
+
+    #[test]
+    fn parses_topics() {
+        assert_eq!(
+            Topic::parse("positions"),
+            Topic::Event("positions".to_string())
+        );
+        assert_eq!(
+            Topic::parse("fullPlayer"),
+            Topic::Event("fullPlayer".to_string())
+        );
+        assert_eq!(
+            Topic::parse("player:Fargoth"),
+            Topic::Player("fargoth".to_string())
+        );
+        assert_eq!(
+            Topic::parse("cell:Seyda Neen"),
+            Topic::Cell("seyda neen".to_string())
+        );
+    }
+
+    #[test]
+    fn gets_everything_without_subscribing() {
+        let subscription = Subscription::default();
+        assert!(subscription.wants_event("positions"));
+        assert!(subscription.wants_event("fullPlayer"));
+        assert!(!subscription.wants_event("playerDelta"));
+        assert!(!subscription.has_player_filter());
+        assert!(subscription.wants_player("Fargoth", Some("Seyda Neen")));
+    }
+
+    #[test]
+    fn event_topics_select_events() {
+        let subscription = subscribe(&["positions", "fullPlayer"]);
+        assert!(subscription.wants_event("positions"));
+        assert!(subscription.wants_event("fullPlayer"));
+        assert!(!subscription.wants_event("playerConnected"));
+        assert!(subscription.wants_player("Fargoth", Some("Seyda Neen")));
+    }
+
+    #[test]
+    fn player_filter_only_gets_every_event_for_those_players() {
+        let subscription = subscribe(&["player:fargoth"]);
+        assert!(subscription.has_player_filter());
+        assert!(subscription.wants_event("positions"));
+        assert!(subscription.wants_event("playerConnected"));
+        assert!(!subscription.wants_event("playerDelta"));
+        assert!(subscription.wants_player("Fargoth", Some("Seyda Neen")));
+        assert!(!subscription.wants_player("Vivec", Some("Seyda Neen")));
+
+        assert!(subscription.filter(&connected("Fargoth")).is_some());
+        assert!(subscription.filter(&connected("Vivec")).is_none());
+    }
+
+    #[test]
+    fn cell_topics_match_players_in_the_cell() {
+        let subscription = subscribe(&["positions", "cell:balmora"]);
+        let event = WebsocketEvent::PlayerPosition(PlayerPositionEvent {
+            positions: vec![
+                position("Fargoth", "Seyda Neen"),
+                position("Vivec", "Balmora"),
+            ],
+        });
+
+        match subscription.filter(&event).as_deref() {
+            Some(WebsocketEvent::PlayerPosition(event)) => {
+                assert_eq!(event.positions.len(), 1);
+                assert_eq!(event.positions[0].name, "Vivec");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let elsewhere = WebsocketEvent::PlayerPosition(PlayerPositionEvent {
+            positions: vec![position("Fargoth", "Seyda Neen")],
+        });
+        assert!(subscription.filter(&elsewhere).is_none());
+        assert!(subscription.filter(&connected("Vivec")).is_none());
+    }
+
+    #[test]
+    fn delta_subscription_replaces_full_player() {
+        let mut subscription = subscribe(&["positions", "fullPlayer", "playerDelta"]);
+        assert!(subscription.follows_deltas());
+        assert!(subscription.wants_event("playerDelta"));
+        assert!(!subscription.wants_event("fullPlayer"));
+        assert!(subscription.wants_event("positions"));
+
+        subscription.apply(&ClientMessage {
+            unsubscribe: vec!["playerDelta".to_string()],
+            ..ClientMessage::default()
+        });
+        assert!(!subscription.wants_event("playerDelta"));
+        assert!(subscription.wants_event("fullPlayer"));
+    }
+
+    #[test]
+    fn cell_topics_dont_apply_to_deltas() {
+        let delta = |name: &str, cell: &str| PlayerDelta {
+            id: 1,
+            name: name.to_string(),
+            cell: cell.to_string(),
+            fields: Default::default(),
+        };
+        let event = WebsocketEvent::PlayerDelta(PlayerDeltaEvent {
+            seq: 2,
+            players: vec![delta("Fargoth", "Seyda Neen"), delta("Vivec", "Balmora")],
+            removed: vec![],
+        });
+
+        let count = |subscription: &Subscription| match subscription.filter(&event).as_deref() {
+            Some(WebsocketEvent::PlayerDelta(event)) => event.players.len(),
+            other => panic!("unexpected {:?}", other),
+        };
+
+        assert_eq!(count(&subscribe(&["playerDelta", "cell:balmora"])), 2);
+        assert_eq!(
+            count(&subscribe(&["playerDelta", "cell:balmora", "player:vivec"])),
+            1
+        );
+
+        // Positions are still limited to the cell
+        let positions = WebsocketEvent::PlayerPosition(PlayerPositionEvent {
+            positions: vec![position("Fargoth", "Seyda Neen")],
+        });
+        assert!(subscribe(&["playerDelta", "positions", "cell:balmora"])
+            .filter(&positions)
+            .is_none());
+    }
+
+    #[test]
+    fn unsubscribing_everything_gets_nothing() {
+        let mut subscription = subscribe(&["positions"]);
+        subscription.apply(&ClientMessage {
+            unsubscribe: vec!["positions".to_string()],
+            ..ClientMessage::default()
+        });
+
+        assert!(!subscription.wants_event("positions"));
+        assert!(!subscription.wants_event("fullPlayer"));
+    }
+
+    #[test]
+    fn parses_sse_topic_lists() {
+        let subscription = Subscription::from_topics(" positions, ,player:Fargoth ");
+        assert!(subscription.wants_event("positions"));
+        assert!(!subscription.wants_event("fullPlayer"));
+        assert!(subscription.wants_player("FARGOTH", Some("Seyda Neen")));
+
+        assert!(Subscription::from_topics("").wants_event("fullPlayer"));
+    }
+
+    #[test]
+    fn private_chat_needs_permission() {
+        let chat = |private| {
+            WebsocketEvent::ChatMessage(ChatMessage {
+                id: 1,
+                player_id: 0,
+                name: "Fargoth".to_string(),
+                cell: "Seyda Neen".to_string(),
+                message: "/msg 1 psst".to_string(),
+                private,
+                timestamp: 0,
+            })
+        };
+
+        let anonymous = Subscription::default();
+        assert!(anonymous.filter(&chat(false)).is_some());
+        assert!(anonymous.filter(&chat(true)).is_none());
+
+        let authenticated = Subscription::default().private_chat(true);
+        assert!(authenticated.filter(&chat(true)).is_some());
+    }
+
+    #[test]
+    fn wants_snapshot_on_resync_or_delta_subscription() {
+        assert!(ClientMessage {
+            resync: true,
+            ..ClientMessage::default()
+        }
+        .wants_snapshot());
+        assert!(ClientMessage {
+            subscribe: vec!["playerDelta".to_string()],
+            ..ClientMessage::default()
+        }
+        .wants_snapshot());
+        assert!(!ClientMessage::default().wants_snapshot());
+    }
+}
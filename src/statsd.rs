@@ -0,0 +1,274 @@
+use crate::config::{StatsdConfig, CONFIG};
+use crate::plugin::{log_message, LOG_INFO, LOG_WARN};
+use lazy_static::lazy_static;
+use std::io::{Error, ErrorKind};
+use std::net::{ToSocketAddrs, UdpSocket};
+
+/// Stays below a 1500 byte MTU so datagrams aren't fragmented
+const MAX_DATAGRAM: usize = 1432;
+
+lazy_static! {
+    /// `None` while StatsD is disabled or the address couldn't be resolved
+    static ref SOCKET: Option<UdpSocket> = connect();
+}
+
+fn connect() -> Option<UdpSocket> {
+    let statsd = &CONFIG.statsd;
+    if !statsd.enabled {
+        return None;
+    }
+
+    let result = statsd
+        .address
+        .to_socket_addrs()
+        .and_then(|mut addrs| {
+            addrs
+                .next()
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "address didn't resolve"))
+        })
+        .and_then(|addr| {
+            let bind = if addr.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let socket = UdpSocket::bind(bind)?;
+            socket.connect(addr)?;
+            // Metrics are sent from the server thread, they must never block a tick
+            socket.set_nonblocking(true)?;
+            Ok(socket)
+        });
+
+    match result {
+        Ok(socket) => {
+            log_message(
+                LOG_INFO,
+                format!("Sending StatsD metrics to {}", statsd.address).as_str(),
+            );
+            Some(socket)
+        }
+        Err(err) => {
+            log_message(
+                LOG_WARN,
+                format!(
+                    "Failed to set up StatsD for {}, disabling it: {}",
+                    statsd.address, err
+                )
+                .as_str(),
+            );
+            None
+        }
+    }
+}
+
+/// Sets up the socket, so a bad address is reported on startup instead of with the first metric
+pub fn init() {
+    lazy_static::initialize(&SOCKET);
+}
+
+/// Replaces everything that has a meaning in either dialect, player and cell names can contain anything
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            c if c.is_alphanumeric() || c == '-' || c == '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn format_line(
+    statsd: &StatsdConfig,
+    name: &str,
+    value: f64,
+    kind: &str,
+    tags: &[(&str, &str)],
+) -> String {
+    let mut line = format!("{}{}", statsd.prefix, name);
+
+    if !statsd.dogstatsd {
+        // Plain StatsD has no tags, their values become part of the name instead
+        for (_, value) in tags {
+            line.push('.');
+            line.push_str(&sanitize(value));
+        }
+
+        return format!("{}:{}|{}", line, value, kind);
+    }
+
+    let tags: Vec<String> = statsd
+        .tags
+        .iter()
+        .cloned()
+        .chain(
+            tags.iter()
+                .map(|(tag, value)| format!("{}:{}", tag, sanitize(value))),
+        )
+        .collect();
+
+    if tags.is_empty() {
+        format!("{}:{}|{}", line, value, kind)
+    } else {
+        format!("{}:{}|{}|#{}", line, value, kind, tags.join(","))
+    }
+}
+
+/// Collects metrics and sends them in as few datagrams as possible once dropped
+#[derive(Default)]
+pub struct Batch {
+    buffer: String,
+}
+
+impl Batch {
+    pub fn gauge(&mut self, name: &str, value: f64, tags: &[(&str, &str)]) {
+        // Plain StatsD reads a signed gauge as a change to the current value
+        if value < 0.0 && !CONFIG.statsd.dogstatsd {
+            self.push(name, 0.0, "g", tags);
+        }
+
+        self.push(name, value, "g", tags);
+    }
+
+    pub fn count(&mut self, name: &str, value: f64, tags: &[(&str, &str)]) {
+        self.push(name, value, "c", tags);
+    }
+
+    /// Records a duration in seconds, sent as milliseconds
+    pub fn timing(&mut self, name: &str, seconds: f64, tags: &[(&str, &str)]) {
+        self.push(name, seconds * 1000.0, "ms", tags);
+    }
+
+    fn push(&mut self, name: &str, value: f64, kind: &str, tags: &[(&str, &str)]) {
+        if SOCKET.is_none() {
+            return;
+        }
+
+        let line = format_line(&CONFIG.statsd, name, value, kind, tags);
+        if !self.buffer.is_empty() && self.buffer.len() + 1 + line.len() > MAX_DATAGRAM {
+            self.flush();
+        }
+
+        if !self.buffer.is_empty() {
+            self.buffer.push('\n');
+        }
+        self.buffer.push_str(&line);
+    }
+
+    fn flush(&mut self) {
+        if let Some(socket) = SOCKET.as_ref() {
+            if !self.buffer.is_empty() {
+                // Best effort like StatsD itself, a full socket buffer or a missing daemon loses the metrics
+                let _ = socket.send(self.buffer.as_bytes());
+            }
+        }
+
+        self.buffer.clear();
+    }
+}
+
+impl Drop for Batch {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+pub fn gauge(name: &str, value: f64, tags: &[(&str, &str)]) {
+    Batch::default().gauge(name, value, tags);
+}
+
+pub fn count(name: &str, value: f64, tags: &[(&str, &str)]) {
+    Batch::default().count(name, value, tags);
+}
+
+pub fn timing(name: &str, seconds: f64, tags: &[(&str, &str)]) {
+    Batch::default().timing(name, seconds, tags);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(dogstatsd: bool, tags: &[&str]) -> StatsdConfig {
+        StatsdConfig {
+            dogstatsd,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..StatsdConfig::default()
+        }
+    }
+
+    #[test]
+    fn sanitizes_names() {
+        assert_eq!(sanitize("Fargoth"), "Fargoth");
+        assert_eq!(sanitize("hash-1_a"), "hash-1_a");
+        assert_eq!(
+            sanitize("Seyda Neen, Census and Excise Office"),
+            "Seyda_Neen__Census_and_Excise_Office"
+        );
+        assert_eq!(sanitize("a:b|c#d@e.f"), "a_b_c_d_e_f");
+        assert_eq!(sanitize("Ald'ruhn"), "Ald_ruhn");
+        assert_eq!(sanitize("Vivec\n"), "Vivec_");
+    }
+
+    #[test]
+    fn formats_plain_statsd() {
+        let config = config(false, &["env:prod"]);
+
+        assert_eq!(
+            format_line(&config, "players.online", 3.0, "g", &[]),
+            "openmw.players.online:3|g"
+        );
+        // Tags become part of the name, static tags aren't sent at all
+        assert_eq!(
+            format_line(
+                &config,
+                "player.health",
+                42.5,
+                "g",
+                &[("player", "Fargoth"), ("cell", "Seyda Neen")]
+            ),
+            "openmw.player.health.Fargoth.Seyda_Neen:42.5|g"
+        );
+        assert_eq!(
+            format_line(&config, "tick.duration", 12.0, "ms", &[]),
+            "openmw.tick.duration:12|ms"
+        );
+    }
+
+    #[test]
+    fn formats_dogstatsd_tags() {
+        let dogstatsd = config(true, &[]);
+        assert_eq!(
+            format_line(&dogstatsd, "players.online", 3.0, "g", &[]),
+            "openmw.players.online:3|g"
+        );
+        assert_eq!(
+            format_line(
+                &dogstatsd,
+                "player.kills",
+                1.0,
+                "c",
+                &[("player", "Fargoth:1")]
+            ),
+            "openmw.player.kills:1|c|#player:Fargoth_1"
+        );
+
+        let tagged = config(true, &["env:prod", "region:eu"]);
+        assert_eq!(
+            format_line(&tagged, "cell.players", 2.0, "g", &[("cell", "Balmora")]),
+            "openmw.cell.players:2|g|#env:prod,region:eu,cell:Balmora"
+        );
+    }
+
+    #[test]
+    fn uses_the_prefix() {
+        let config = StatsdConfig {
+            prefix: String::new(),
+            ..StatsdConfig::default()
+        };
+
+        assert_eq!(
+            format_line(&config, "players.online", 0.0, "g", &[]),
+            "players.online:0|g"
+        );
+    }
+}
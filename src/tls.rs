@@ -0,0 +1,130 @@
+use crate::config::CONFIG;
+use crate::plugin::{log_message, LOG_ERROR, LOG_INFO};
+use futures_util::future::abortable;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+use tokio::select;
+use tokio::time::interval;
+use warp::filters::path::FullPath;
+use warp::filters::BoxedFilter;
+use warp::http::{StatusCode, Uri};
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+pub type Routes = BoxedFilter<(Response,)>;
+
+/// Modification times of the certificate and key, `None` while either is missing
+fn modified() -> Option<(SystemTime, SystemTime)> {
+    let tls = &CONFIG.tls;
+    let cert = std::fs::metadata(&tls.cert)
+        .and_then(|m| m.modified())
+        .ok()?;
+    let key = std::fs::metadata(&tls.key)
+        .and_then(|m| m.modified())
+        .ok()?;
+
+    Some((cert, key))
+}
+
+async fn wait_for_change(previous: Option<(SystemTime, SystemTime)>) {
+    let mut check = interval(Duration::from_secs(CONFIG.tls.reload_interval));
+    check.tick().await;
+
+    loop {
+        check.tick().await;
+        let current = modified();
+        if current.is_some() && current != previous {
+            return;
+        }
+    }
+}
+
+/// Serves the routes over HTTPS, restarting the listener whenever the certificate or key changes.
+/// Open connections keep using the certificate they were accepted with
+pub async fn serve_tls(routes: Routes) {
+    loop {
+        let files = modified();
+        let (server, abort) = abortable(
+            warp::serve(routes.clone())
+                .tls()
+                .cert_path(&CONFIG.tls.cert)
+                .key_path(&CONFIG.tls.key)
+                .run(CONFIG.listen_addr()),
+        );
+
+        // warp panics on an invalid certificate, the task catches that instead of taking down the HTTP thread
+        let mut server = tokio::spawn(server);
+        log_message(
+            LOG_INFO,
+            format!("Serving HTTPS on {}", CONFIG.listen_addr()).as_str(),
+        );
+
+        select! {
+            result = &mut server => {
+                if let Err(err) = result {
+                    log_message(
+                        LOG_ERROR,
+                        format!("HTTPS server failed, waiting for a new certificate: {}", err).as_str(),
+                    );
+                }
+
+                wait_for_change(files).await;
+            }
+            _ = wait_for_change(files) => {
+                abort.abort();
+                let _ = server.await;
+            }
+        }
+
+        log_message(LOG_INFO, "TLS certificate changed, reloading");
+    }
+}
+
+/// Strips the port from a `Host` header, keeping IPv6 addresses in brackets intact
+fn host_name(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(index) if !host[index..].contains(']') => &host[..index],
+        _ => host,
+    }
+}
+
+/// Redirects every plain HTTP request on `port` to the HTTPS listener
+pub async fn redirect_http(port: u16) {
+    let https_port = CONFIG.port;
+    let redirect = warp::header::optional::<String>("host")
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .map(move |host: Option<String>, path: FullPath, query: String| {
+            let host = match host {
+                Some(host) => host,
+                None => {
+                    return warp::reply::with_status("Missing Host header", StatusCode::BAD_REQUEST)
+                        .into_response()
+                }
+            };
+
+            let mut location = format!("https://{}", host_name(&host));
+            if https_port != 443 {
+                location.push_str(&format!(":{}", https_port));
+            }
+
+            location.push_str(path.as_str());
+            if !query.is_empty() {
+                location.push('?');
+                location.push_str(&query);
+            }
+
+            match location.parse::<Uri>() {
+                Ok(uri) => warp::redirect(uri).into_response(),
+                Err(_) => warp::reply::with_status("Invalid Host header", StatusCode::BAD_REQUEST)
+                    .into_response(),
+            }
+        });
+
+    let addr = SocketAddr::new(CONFIG.listen_addr().ip(), port);
+    log_message(
+        LOG_INFO,
+        format!("Redirecting HTTP on {} to HTTPS", addr).as_str(),
+    );
+    warp::serve(redirect).run(addr).await
+}